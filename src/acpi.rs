@@ -0,0 +1,457 @@
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+
+// Where the BIOS leaves the RSDP: the first KiB of the EBDA, whose segment is stored at 0x40E,
+// or the read-only BIOS area below 1MiB, always on a 16 byte boundary
+const EBDA_POINTER: u64 = 0x40E;
+const BIOS_AREA: (u64, u64) = (0xE_0000, 0x10_0000);
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+// Size of the header every system description table starts with
+const SDT_HEADER_SIZE: usize = 36;
+
+/// A four character table signature, like "APIC" for the MADT
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 4]);
+
+impl Signature {
+    pub const MADT: Signature = Signature(*b"APIC");
+    pub const FADT: Signature = Signature(*b"FACP");
+    pub const HPET: Signature = Signature(*b"HPET");
+    pub const DSDT: Signature = Signature(*b"DSDT");
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &byte in self.0.iter() {
+            write!(f, "{}", byte as char)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    BadChecksum(Signature),
+    UnexpectedSignature(Signature),
+    TooShort(Signature),
+}
+
+/// A register location as ACPI describes it (the Generic Address Structure)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8, // 0 is memory, 1 is I/O ports
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    fn parse(bytes: &[u8]) -> Self {
+        GenericAddress {
+            address_space: bytes[0],
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+}
+
+/// A processor listed in the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+/// An I/O APIC listed in the MADT, handling the GSIs from 'gsi_base' on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't wired to the I/O APIC input of the same number, or not with the ISA defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16, // Polarity in bits 0-1 and trigger mode in bits 2-3, 3 meaning active low or level
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// The interrupt controllers from the MADT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub has_8259: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// The GSI the ISA IRQ arrives on, along with its override if it has one
+    pub fn isa_irq(&self, irq: u8) -> (u32, Option<InterruptOverride>) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, Some(*o)),
+            None => (irq as u32, None),
+        }
+    }
+}
+
+/// The power management parts of the FADT (ports are 0 when the block doesn't exist)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub century_register: u8, // The CMOS register holding the century, 0 if there is none
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>, // Only set if the firmware says it works
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    const RESET_REG_SUPPORTED: u32 = 1 << 10;
+}
+
+/// The HPET description
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub number: u8,
+    pub minimum_tick: u16,
+}
+
+/// Everything the kernel took from the ACPI tables
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcpiTables {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub tables: Vec<(Signature, PhysAddr)>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+}
+
+impl AcpiTables {
+    /// The physical address of the first table with the signature
+    pub fn find(&self, signature: Signature) -> Option<PhysAddr> {
+        self.tables.iter().find(|(s, _)| *s == signature).map(|(_, addr)| *addr)
+    }
+}
+
+static TABLES: Mutex<Option<AcpiTables>> = Mutex::new(None);
+
+/// Finds and parses the ACPI tables, needs the physical memory mapping and the heap
+pub fn init() -> Result<(), AcpiError> {
+    let tables = parse_tables(find_rsdp()?)?;
+    x86_64::instructions::interrupts::without_interrupts(|| *TABLES.lock() = Some(tables));
+    Ok(())
+}
+
+/// The parsed tables, or 'None' if 'init' didn't find any
+pub fn tables() -> Option<AcpiTables> {
+    x86_64::instructions::interrupts::without_interrupts(|| TABLES.lock().clone())
+}
+
+/// The parsed MADT, if there is one
+pub fn madt() -> Option<Madt> {
+    tables().and_then(|tables| tables.madt)
+}
+
+/// The parsed FADT, if there is one
+pub fn fadt() -> Option<Fadt> {
+    tables().and_then(|tables| tables.fadt)
+}
+
+/// The parsed HPET table, if there is one
+pub fn hpet() -> Option<Hpet> {
+    tables().and_then(|tables| tables.hpet)
+}
+
+/// The bytes of the system description table at 'addr', header included, after checking its checksum
+pub fn table_bytes(addr: PhysAddr) -> Result<&'static [u8], AcpiError> {
+    let header = unsafe { physical_bytes(addr, SDT_HEADER_SIZE) };
+    let signature = Signature([header[0], header[1], header[2], header[3]]);
+    let length = read_u32(header, 4) as usize;
+    if length < SDT_HEADER_SIZE {
+        return Err(AcpiError::TooShort(signature));
+    }
+    let bytes = unsafe { physical_bytes(addr, length) };
+    if !checksum_ok(bytes) {
+        return Err(AcpiError::BadChecksum(signature));
+    }
+    Ok(bytes)
+}
+
+// Returns the physical address of a valid RSDP
+fn find_rsdp() -> Result<PhysAddr, AcpiError> {
+    let ebda = (read_u16(unsafe { physical_bytes(PhysAddr::new(EBDA_POINTER), 2) }, 0) as u64) << 4;
+    let mut candidates = (ebda..ebda + 1024).step_by(16).filter(|_| ebda != 0)
+        .chain((BIOS_AREA.0..BIOS_AREA.1).step_by(16));
+
+    candidates
+        .find(|&addr| {
+            let bytes = unsafe { physical_bytes(PhysAddr::new(addr), 20) };
+            &bytes[..8] == RSDP_SIGNATURE && checksum_ok(bytes)
+        })
+        .map(PhysAddr::new)
+        .ok_or(AcpiError::RsdpNotFound)
+}
+
+// Walks the RSDT or XSDT the RSDP points to and parses the tables the kernel uses
+fn parse_tables(rsdp_addr: PhysAddr) -> Result<AcpiTables, AcpiError> {
+    let rsdp = unsafe { physical_bytes(rsdp_addr, 20) };
+    let revision = rsdp[15];
+    let mut oem_id = [0; 6];
+    oem_id.copy_from_slice(&rsdp[9..15]);
+
+    // ACPI 2.0 and later have a longer RSDP with its own checksum and a 64 bit XSDT
+    let xsdt = if revision >= 2 {
+        let length = read_u32(unsafe { physical_bytes(rsdp_addr, 24) }, 20) as usize;
+        let extended = unsafe { physical_bytes(rsdp_addr, length) };
+        if !checksum_ok(extended) {
+            return Err(AcpiError::BadChecksum(Signature(*b"RSD ")));
+        }
+        Some(read_u64(extended, 24)).filter(|&addr| addr != 0)
+    } else {
+        None
+    };
+    let (root, entry_size, signature) = match xsdt {
+        Some(addr) => (PhysAddr::new(addr), 8, Signature(*b"XSDT")),
+        None => (PhysAddr::new(read_u32(rsdp, 16) as u64), 4, Signature(*b"RSDT")),
+    };
+
+    let root = table_bytes(root)?;
+    if root[..4] != signature.0 {
+        return Err(AcpiError::UnexpectedSignature(Signature([root[0], root[1], root[2], root[3]])));
+    }
+    let mut tables = AcpiTables { revision, oem_id, tables: Vec::new(), madt: None, fadt: None, hpet: None };
+    let entries = (SDT_HEADER_SIZE..root.len()).step_by(entry_size).filter_map(|offset| {
+        let addr = PhysAddr::new(if entry_size == 8 { read_u64(root, offset) } else { read_u32(root, offset) as u64 });
+        table_bytes(addr).ok().map(|bytes| (addr, bytes))
+    });
+    add_tables(&mut tables, entries);
+    if let Some(fadt) = tables.fadt {
+        tables.tables.push((Signature::DSDT, fadt.dsdt));
+    }
+    Ok(tables)
+}
+
+// Lists the tables and parses the ones the kernel uses
+//
+// A broken table is skipped and left out of the list, the rest can still be used
+fn add_tables<'a>(tables: &mut AcpiTables, entries: impl Iterator<Item = (PhysAddr, &'a [u8])>) {
+    for (addr, bytes) in entries {
+        let signature = Signature([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let parsed = match signature {
+            Signature::MADT => parse_madt(bytes).map(|madt| tables.madt = Some(madt)),
+            Signature::FADT => parse_fadt(bytes).map(|fadt| tables.fadt = Some(fadt)),
+            Signature::HPET => parse_hpet(bytes).map(|hpet| tables.hpet = Some(hpet)),
+            _ => Ok(()),
+        };
+        if parsed.is_ok() {
+            tables.tables.push((signature, addr));
+        }
+    }
+}
+
+fn parse_madt(bytes: &[u8]) -> Result<Madt, AcpiError> {
+    if bytes.len() < SDT_HEADER_SIZE + 8 {
+        return Err(AcpiError::TooShort(Signature::MADT));
+    }
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read_u32(bytes, 36) as u64),
+        has_8259: read_u32(bytes, 40) & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    // Variable sized entries, each starting with its type and length
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= bytes.len() {
+        let (kind, length) = (bytes[offset], bytes[offset + 1] as usize);
+        if length < 2 || offset + length > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..offset + length];
+        match (kind, length) {
+            (0, 8) => madt.processors.push(Processor {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                enabled: read_u32(entry, 4) & 1 != 0,
+            }),
+            (1, 12) => madt.io_apics.push(IoApicInfo {
+                id: entry[2],
+                address: PhysAddr::new(read_u32(entry, 4) as u64),
+                gsi_base: read_u32(entry, 8),
+            }),
+            (2, 10) => madt.overrides.push(InterruptOverride {
+                irq: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            }),
+            (5, 12) => madt.local_apic_address = PhysAddr::new(read_u64(entry, 4)),
+            _ => {}
+        }
+        offset += length;
+    }
+    Ok(madt)
+}
+
+fn parse_fadt(bytes: &[u8]) -> Result<Fadt, AcpiError> {
+    // Everything up to the century register exists since ACPI 1.0
+    if bytes.len() < 116 {
+        return Err(AcpiError::TooShort(Signature::FADT));
+    }
+    let flags = read_u32(bytes, 112);
+    // The 64 bit DSDT pointer wins if it's there
+    let dsdt = match bytes.len() >= 148 && read_u64(bytes, 140) != 0 {
+        true => read_u64(bytes, 140),
+        false => read_u32(bytes, 40) as u64,
+    };
+    let reset = bytes.len() >= 129 && flags & Fadt::RESET_REG_SUPPORTED != 0;
+
+    Ok(Fadt {
+        dsdt: PhysAddr::new(dsdt),
+        sci_interrupt: read_u16(bytes, 46),
+        smi_command_port: read_u32(bytes, 48),
+        acpi_enable: bytes[52],
+        acpi_disable: bytes[53],
+        pm1a_event_block: read_u32(bytes, 56),
+        pm1b_event_block: read_u32(bytes, 60),
+        pm1a_control_block: read_u32(bytes, 64),
+        pm1b_control_block: read_u32(bytes, 68),
+        pm_timer_block: read_u32(bytes, 76),
+        century_register: bytes[108],
+        flags,
+        reset_register: if reset { Some(GenericAddress::parse(&bytes[116..128])) } else { None },
+        reset_value: if reset { bytes[128] } else { 0 },
+    })
+}
+
+fn parse_hpet(bytes: &[u8]) -> Result<Hpet, AcpiError> {
+    if bytes.len() < 56 {
+        return Err(AcpiError::TooShort(Signature::HPET));
+    }
+    Ok(Hpet {
+        event_timer_block_id: read_u32(bytes, 36),
+        base_address: GenericAddress::parse(&bytes[40..52]),
+        number: bytes[52],
+        minimum_tick: read_u16(bytes, 53),
+    })
+}
+
+// Every byte of a checksummed structure adds up to 0
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+// The physical memory at 'addr' through the bootloader's mapping of all of it
+//
+// Unsafe as the range has to be mapped there and must not be written to while the slice lives
+unsafe fn physical_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+// Test that QEMU's tables are found and describe at least one processor and I/O APIC
+#[test_case]
+fn test_tables_found() {
+    let tables = tables().expect("no ACPI tables found");
+    let madt = tables.madt.as_ref().expect("no MADT");
+    assert!(madt.processors.iter().any(|p| p.enabled));
+    assert!(!madt.io_apics.is_empty());
+    assert!(tables.fadt.expect("no FADT").pm1a_control_block != 0);
+    assert!(tables.find(Signature::DSDT).is_some());
+}
+
+// Test that the MADT entries are parsed and broken checksums are caught
+#[test_case]
+fn test_parse_madt() {
+    let mut bytes = alloc::vec![0u8; SDT_HEADER_SIZE + 8];
+    bytes[..4].copy_from_slice(b"APIC");
+    bytes[36..40].copy_from_slice(&0xFEE0_0000u32.to_le_bytes());
+    // A processor, an I/O APIC and IRQ 0 moved to GSI 2
+    bytes.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    bytes.extend_from_slice(&[1, 12, 0, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+    bytes.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+    let length = bytes.len() as u32;
+    bytes[4..8].copy_from_slice(&length.to_le_bytes());
+    bytes[9] = 0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+    assert!(checksum_ok(&bytes));
+
+    let madt = parse_madt(&bytes).unwrap();
+    assert_eq!(madt.local_apic_address, PhysAddr::new(0xFEE0_0000));
+    assert_eq!(madt.processors, [Processor { processor_id: 0, apic_id: 0, enabled: true }]);
+    assert_eq!(madt.io_apics[0].address, PhysAddr::new(0xFEC0_0000));
+    assert_eq!(madt.isa_irq(0).0, 2);
+    assert_eq!(madt.isa_irq(1), (1, None));
+
+    bytes[40] ^= 1;
+    assert!(!checksum_ok(&bytes));
+}
+
+// Test that a broken table doesn't keep the ones next to it from being used
+#[test_case]
+fn test_broken_table_skipped() {
+    let mut hpet = alloc::vec![0u8; 56];
+    hpet[..4].copy_from_slice(b"HPET");
+    hpet[52] = 3;
+    // Too short for even the MADT's fixed fields
+    let mut madt = alloc::vec![0u8; SDT_HEADER_SIZE];
+    madt[..4].copy_from_slice(b"APIC");
+
+    let mut tables = AcpiTables { revision: 0, oem_id: [0; 6], tables: Vec::new(), madt: None, fadt: None, hpet: None };
+    let entries = [(PhysAddr::new(0x1000), &madt[..]), (PhysAddr::new(0x2000), &hpet[..])];
+    add_tables(&mut tables, entries.iter().copied());
+    assert!(tables.madt.is_none());
+    assert_eq!(tables.hpet.map(|hpet| hpet.number), Some(3));
+    assert_eq!(tables.tables, [(Signature::HPET, PhysAddr::new(0x2000))]);
+}
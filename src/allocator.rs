@@ -0,0 +1,181 @@
+use core::alloc::{GlobalAlloc, Layout};
+use x86_64::{
+    structures::paging::{mapper::MapToError, OffsetPageTable, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+use crate::memory::{bitmap::BitmapFrameAllocator, huge};
+
+pub mod bump;
+pub mod linked_list;
+pub mod fixed_size_block;
+#[cfg(feature = "alloc-tracking")]
+pub mod tracking;
+
+// The virtual address the kernel heap starts at (chosen so it's easy to spot in a debugger, and 2MiB aligned)
+pub const HEAP_START: usize = 0x_4444_4440_0000;
+// The size of the kernel heap, exactly one huge page
+pub const HEAP_SIZE: usize = 2 * 1024 * 1024; // 2 MiB
+
+// Pick the heap design from the enabled cargo features (earlier ones win if several are enabled)
+#[cfg(feature = "bump-allocator")]
+type KernelAllocator = bump::BumpAllocator;
+#[cfg(all(feature = "linked-list-allocator", not(feature = "bump-allocator")))]
+type KernelAllocator = linked_list::LinkedListAllocator;
+#[cfg(all(
+    feature = "fixed-size-block-allocator",
+    not(any(feature = "bump-allocator", feature = "linked-list-allocator"))
+))]
+type KernelAllocator = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(not(any(
+    feature = "bump-allocator",
+    feature = "linked-list-allocator",
+    feature = "fixed-size-block-allocator"
+)))]
+compile_error!("one of the heap allocator features must be enabled");
+
+// The allocator used by the 'alloc' crate for Box, Vec, etc.
+#[global_allocator]
+static ALLOCATOR: Locked<KernelAllocator> = Locked::new(KernelAllocator::new());
+
+/// The interface every heap design implements so it can be plugged in as the global allocator
+pub trait HeapAllocator {
+    /// Hands the allocator the memory it manages
+    ///
+    /// Unsafe as the caller must guarantee the given range is mapped and unused.
+    /// Must only be called once
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /// Allocates memory for the given layout, returning a null pointer when out of memory
+    fn alloc(&mut self, layout: Layout) -> *mut u8;
+
+    /// Frees memory previously returned by 'alloc' with the same layout
+    ///
+    /// Unsafe as the caller must guarantee the pointer and layout match an earlier allocation
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
+}
+
+/// Heap counters since boot, in bytes as requested by the callers (not counting padding)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub failed_allocations: usize,
+}
+
+impl HeapStats {
+    const fn new() -> Self {
+        HeapStats {
+            size: 0,
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            allocations: 0,
+            deallocations: 0,
+            failed_allocations: 0,
+        }
+    }
+}
+
+/// A wrapper around spin::Mutex so we can implement foreign traits like GlobalAlloc on it
+///
+/// Also keeps the heap counters for allocations made through GlobalAlloc
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+    stats: spin::Mutex<HeapStats>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+            stats: spin::Mutex::new(HeapStats::new()),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+
+    /// A snapshot of the counters
+    pub fn stats(&self) -> HeapStats {
+        *self.stats.lock()
+    }
+}
+
+unsafe impl<A: HeapAllocator> GlobalAlloc for Locked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.lock().alloc(layout);
+        let mut stats = self.stats.lock();
+        if ptr.is_null() {
+            stats.failed_allocations += 1;
+            return ptr;
+        }
+        stats.allocations += 1;
+        stats.bytes_in_use += layout.size();
+        stats.peak_bytes_in_use = stats.peak_bytes_in_use.max(stats.bytes_in_use);
+        #[cfg(feature = "alloc-tracking")]
+        tracking::record_alloc(ptr as usize, layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().dealloc(ptr, layout);
+        let mut stats = self.stats.lock();
+        stats.deallocations += 1;
+        stats.bytes_in_use -= layout.size();
+        #[cfg(feature = "alloc-tracking")]
+        tracking::record_dealloc(ptr as usize);
+    }
+}
+
+/// The kernel heap's counters
+pub fn stats() -> HeapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| ALLOCATOR.stats())
+}
+
+/// Aligns the given address upwards to the given alignment (which must be a power of two)
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Maps the heap's virtual pages to physical frames and hands the region to the global allocator
+///
+/// Needs to be called once before anything in the 'alloc' crate is used
+pub fn init_heap(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+    // Back the heap with newly allocated frames, using a huge page if there's a contiguous one left
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    huge::map_fresh(mapper, frame_allocator, VirtAddr::new(HEAP_START as u64), HEAP_SIZE as u64, flags)?;
+
+    // Only give the allocator the region once it's actually mapped
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.stats.lock().size = HEAP_SIZE;
+    }
+
+    Ok(())
+}
+
+// A chunk of static memory the allocator designs can be stress tested on without the real heap
+#[cfg(test)]
+fn test_heap_region() -> (usize, usize) {
+    const TEST_HEAP_SIZE: usize = 32 * 1024;
+
+    #[repr(align(4096))]
+    struct TestHeap([u8; TEST_HEAP_SIZE]);
+    static mut TEST_HEAP: TestHeap = TestHeap([0; TEST_HEAP_SIZE]);
+
+    (unsafe { core::ptr::addr_of_mut!(TEST_HEAP) } as usize, TEST_HEAP_SIZE)
+}
+
+#[test_case]
+fn test_align_up() {
+    assert_eq!(align_up(0, 8), 0);
+    assert_eq!(align_up(1, 8), 8);
+    assert_eq!(align_up(8, 8), 8);
+    assert_eq!(align_up(4097, 4096), 8192);
+}
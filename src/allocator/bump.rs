@@ -0,0 +1,101 @@
+use core::alloc::Layout;
+use core::ptr;
+use super::{align_up, HeapAllocator};
+
+/// The simplest heap design: hands out memory linearly and only frees it once every
+/// allocation has been freed. Fast and predictable, so mostly useful for tests
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    next: usize, // The start of the unused memory
+    allocations: usize, // The number of live allocations
+}
+
+impl BumpAllocator {
+    /// Creates an empty allocator, 'init' needs to be called before it can be used
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for BumpAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+
+        if alloc_end > self.heap_end {
+            ptr::null_mut() // Out of memory
+        } else {
+            self.next = alloc_end;
+            self.allocations += 1;
+            alloc_start as *mut u8
+        }
+    }
+
+    unsafe fn dealloc(&mut self, _ptr: *mut u8, _layout: Layout) {
+        self.allocations -= 1;
+        // Everything has been freed, so the whole heap can be reused
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+}
+
+// Test that the allocator fills up, fails cleanly and starts over once everything is freed
+#[test_case]
+fn test_bump_fill_and_reset() {
+    let (heap_start, heap_size) = super::test_heap_region();
+    let mut allocator = BumpAllocator::new();
+    unsafe { allocator.init(heap_start, heap_size) };
+
+    let layout = Layout::from_size_align(64, 16).unwrap();
+    let mut count = 0;
+    loop {
+        let ptr = allocator.alloc(layout);
+        if ptr.is_null() {
+            break;
+        }
+        assert_eq!(ptr as usize % 16, 0);
+        unsafe { ptr.write_bytes(0xab, 64) };
+        count += 1;
+    }
+    assert_eq!(count, heap_size / 64);
+
+    for _ in 0..count {
+        unsafe { allocator.dealloc(ptr::null_mut(), layout) };
+    }
+    assert_eq!(allocator.alloc(layout) as usize, heap_start);
+}
+
+// Test that alignment padding is respected between differently aligned allocations
+#[test_case]
+fn test_bump_alignment() {
+    let (heap_start, heap_size) = super::test_heap_region();
+    let mut allocator = BumpAllocator::new();
+    unsafe { allocator.init(heap_start, heap_size) };
+
+    let small = allocator.alloc(Layout::from_size_align(1, 1).unwrap());
+    let aligned = allocator.alloc(Layout::from_size_align(8, 512).unwrap());
+    assert_eq!(small as usize, heap_start);
+    assert_eq!(aligned as usize, heap_start + 512);
+}
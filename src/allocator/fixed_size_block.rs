@@ -0,0 +1,144 @@
+use core::alloc::Layout;
+use core::mem;
+use super::HeapAllocator;
+use super::linked_list::LinkedListAllocator;
+
+/// The block sizes to use
+///
+/// The sizes must each be power of 2 because they are also used as the block alignment
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// A free block, stored inside the block itself
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// A heap design with a free list per block size, making allocation and freeing O(1)
+///
+/// Allocations bigger than the largest block size (and the blocks themselves) come from a
+/// linked list allocator over the same heap
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty allocator, 'init' needs to be called before it can be used
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+        }
+    }
+
+    /// Allocates using the fallback allocator
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback_allocator.alloc(layout)
+    }
+}
+
+/// Chooses an appropriate block size for the given layout
+///
+/// Returns an index into the 'BLOCK_SIZES' array
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => {
+                match self.list_heads[index].take() {
+                    Some(node) => {
+                        // Pop the first free block of the list
+                        self.list_heads[index] = node.next.take();
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        // No block exists in list => allocate new block
+                        let block_size = BLOCK_SIZES[index];
+                        // Only works if all block sizes are a power of 2
+                        let block_align = block_size;
+                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                        self.fallback_alloc(layout)
+                    }
+                }
+            }
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                // Push the block onto the front of its list
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // Verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => self.fallback_allocator.dealloc(ptr, layout),
+        }
+    }
+}
+
+// Test that freed blocks of every size are handed out again without touching the fallback
+#[test_case]
+fn test_fixed_size_block_reuse() {
+    let (heap_start, heap_size) = super::test_heap_region();
+    let mut allocator = FixedSizeBlockAllocator::new();
+    unsafe { allocator.init(heap_start, heap_size) };
+
+    for &block_size in BLOCK_SIZES {
+        let layout = Layout::from_size_align(block_size, 1).unwrap();
+        let first = allocator.alloc(layout);
+        assert!(!first.is_null());
+        assert_eq!(first as usize % block_size, 0);
+        unsafe { allocator.dealloc(first, layout) };
+        assert_eq!(allocator.alloc(layout), first);
+        unsafe { allocator.dealloc(first, layout) };
+    }
+}
+
+// Test a mix of many small and large allocations with interleaved frees
+#[test_case]
+fn test_fixed_size_block_stress() {
+    let (heap_start, heap_size) = super::test_heap_region();
+    let mut allocator = FixedSizeBlockAllocator::new();
+    unsafe { allocator.init(heap_start, heap_size) };
+
+    let mut allocations = [(core::ptr::null_mut(), Layout::new::<u8>()); 48];
+    for round in 0..100 {
+        for (i, slot) in allocations.iter_mut().enumerate() {
+            // Every eighth allocation is too big for a block and goes to the fallback
+            let size = if i % 8 == 7 { 3000 } else { 1 << (i % 9) };
+            let layout = Layout::from_size_align(size, 8.min(size)).unwrap();
+            let ptr = allocator.alloc(layout);
+            assert!(!ptr.is_null(), "out of memory in round {}", round);
+            unsafe { ptr.write_bytes(i as u8, size) };
+            *slot = (ptr, layout);
+        }
+        for (i, &(ptr, layout)) in allocations.iter().enumerate() {
+            assert_eq!(unsafe { *ptr }, i as u8);
+            assert_eq!(unsafe { *ptr.add(layout.size() - 1) }, i as u8);
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+    }
+}
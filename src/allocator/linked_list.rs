@@ -0,0 +1,219 @@
+use core::alloc::Layout;
+use core::{mem, ptr};
+use super::{align_up, HeapAllocator};
+
+// A free region of the heap, stored inside the region itself
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// A heap design that keeps the free regions in a list sorted by address, merging
+/// neighbouring regions on free so the heap doesn't fragment over time
+pub struct LinkedListAllocator {
+    head: ListNode, // A dummy node with size 0 pointing to the first free region
+}
+
+impl LinkedListAllocator {
+    /// Creates an empty allocator, 'init' needs to be called before it can be used
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ListNode::new(0),
+        }
+    }
+
+    /// Adds the given region to the free list, merging it with the regions it touches
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // The region has to be able to hold a ListNode
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // Find the last region before the new one so the list stays sorted
+        let mut current = &mut self.head;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+
+        let mut node = ListNode::new(size);
+        node.next = current.next.take();
+
+        // Swallow the following region if it starts right where this one ends
+        if node.next.as_ref().is_some_and(|next| next.start_addr() == addr + size) {
+            let following = node.next.take().unwrap();
+            node.size += following.size;
+            node.next = following.next.take();
+        }
+
+        // Grow the preceding region if it ends right where this one starts (never the dummy head)
+        if current.size != 0 && current.end_addr() == addr {
+            current.size += node.size;
+            current.next = node.next.take();
+        } else {
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+        }
+    }
+
+    /// Looks for a free region that fits the given size and alignment and removes it from the list
+    ///
+    /// Returns the region and the start address of the allocation inside it
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                // Region fits, unlink it from the list
+                let next = region.next.take();
+                let found = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+                return found;
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+        None
+    }
+
+    /// Tries to fit an allocation with the given size and alignment into the given region
+    ///
+    /// Any space left before or after the allocation has to be big enough to become a free region again
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let node_size = mem::size_of::<ListNode>();
+
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let front_padding = alloc_start - region.start_addr();
+        if front_padding > 0 && front_padding < node_size {
+            alloc_start = align_up(region.start_addr() + node_size, align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            return Err(()); // Region too small
+        }
+
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < node_size {
+            return Err(()); // The rest of the region can't hold a ListNode
+        }
+
+        Ok(alloc_start)
+    }
+
+    /// Adjusts the given layout so the allocated region can later hold a ListNode
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for LinkedListAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = LinkedListAllocator::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            let alloc_end = alloc_start + size;
+
+            // Give back whatever is left on either side of the allocation
+            unsafe {
+                if region_end > alloc_end {
+                    self.add_free_region(alloc_end, region_end - alloc_end);
+                }
+                if alloc_start > region_start {
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+            }
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        self.add_free_region(ptr as usize, size)
+    }
+}
+
+// Test that freeing everything (in a scrambled order) merges the heap back into one region
+#[test_case]
+fn test_linked_list_merges_neighbours() {
+    let (heap_start, heap_size) = super::test_heap_region();
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(heap_start, heap_size) };
+
+    let mut allocations = [(ptr::null_mut(), Layout::new::<u8>()); 64];
+    for (i, slot) in allocations.iter_mut().enumerate() {
+        let layout = Layout::from_size_align(16 + (i % 7) * 24, 1 << (i % 5 + 3)).unwrap();
+        let ptr = allocator.alloc(layout);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % layout.align(), 0);
+        unsafe { ptr.write_bytes(i as u8, layout.size()) };
+        *slot = (ptr, layout);
+    }
+
+    // Make sure no allocation overwrote another one
+    for (i, &(ptr, layout)) in allocations.iter().enumerate() {
+        for offset in 0..layout.size() {
+            assert_eq!(unsafe { *ptr.add(offset) }, i as u8);
+        }
+    }
+
+    // Free odd entries first so merging has to join regions on both sides
+    for &(ptr, layout) in allocations.iter().skip(1).step_by(2) {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    for &(ptr, layout) in allocations.iter().step_by(2) {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    // Only possible if every freed region was merged back together
+    let whole_heap = Layout::from_size_align(heap_size, 8).unwrap();
+    assert_eq!(allocator.alloc(whole_heap) as usize, heap_start);
+}
+
+// Test that many short lived allocations keep reusing the same memory
+#[test_case]
+fn test_linked_list_reuse() {
+    let (heap_start, heap_size) = super::test_heap_region();
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(heap_start, heap_size) };
+
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let long_lived = allocator.alloc(layout);
+    for _ in 0..heap_size {
+        let ptr = allocator.alloc(layout);
+        assert!(!ptr.is_null());
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    assert_eq!(long_lived as usize, heap_start);
+}
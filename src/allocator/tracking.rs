@@ -0,0 +1,104 @@
+// Attributes heap allocations to call sites, only built with the 'alloc-tracking' feature
+//
+// The allocator can't know who called it, so code marks a region with 'track_site' and
+// every allocation made while the returned guard lives is recorded under that call site
+// until it's freed again
+
+use core::panic::Location;
+use spin::Mutex;
+
+/// How many live allocations can be recorded at once, later ones are only counted as dropped
+pub const MAX_TRACKED: usize = 512;
+
+#[derive(Debug, Clone, Copy)]
+struct LiveAllocation {
+    addr: usize,
+    size: usize,
+    site: &'static Location<'static>,
+}
+
+// The call site allocations are currently attributed to
+static CURRENT_SITE: Mutex<Option<&'static Location<'static>>> = Mutex::new(None);
+
+// Live allocations made while a site was set, kept in a fixed table as the heap can't be used here
+static LIVE: Mutex<([Option<LiveAllocation>; MAX_TRACKED], usize)> = Mutex::new(([None; MAX_TRACKED], 0));
+
+/// Attributes allocations to the caller's location until the guard is dropped
+pub struct SiteGuard {
+    previous: Option<&'static Location<'static>>,
+}
+
+impl Drop for SiteGuard {
+    fn drop(&mut self) {
+        x86_64::instructions::interrupts::without_interrupts(|| *CURRENT_SITE.lock() = self.previous);
+    }
+}
+
+/// Starts attributing allocations to the calling line, guards can be nested
+#[track_caller]
+pub fn track_site() -> SiteGuard {
+    let site = Location::caller();
+    let previous = x86_64::instructions::interrupts::without_interrupts(|| CURRENT_SITE.lock().replace(site));
+    SiteGuard { previous }
+}
+
+// Called by the global allocator for every successful allocation
+pub(super) fn record_alloc(addr: usize, size: usize) {
+    let site = match *CURRENT_SITE.lock() {
+        Some(site) => site,
+        None => return,
+    };
+    let mut live = LIVE.lock();
+    let (table, dropped) = &mut *live;
+    match table.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(LiveAllocation { addr, size, site }),
+        None => *dropped += 1,
+    }
+}
+
+// Called by the global allocator for every deallocation
+pub(super) fn record_dealloc(addr: usize) {
+    let mut live = LIVE.lock();
+    if let Some(slot) = live.0.iter_mut().find(|slot| slot.is_some_and(|a| a.addr == addr)) {
+        *slot = None;
+    }
+}
+
+/// Calls the closure with the call site, address and size of every recorded allocation that's still live
+pub fn for_each_live(mut f: impl FnMut(&'static Location<'static>, usize, usize)) {
+    // Copy the table out, so the closure is free to allocate
+    let table = x86_64::instructions::interrupts::without_interrupts(|| LIVE.lock().0);
+    for allocation in table.iter().flatten() {
+        f(allocation.site, allocation.addr, allocation.size);
+    }
+}
+
+/// The number of allocations that weren't recorded because the table was full
+pub fn dropped() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| LIVE.lock().1)
+}
+
+// Test that allocations are attributed to the guard's line until they're freed
+#[test_case]
+fn test_track_site() {
+    use alloc::boxed::Box;
+
+    let count_live = |line: u32| {
+        let mut count = 0;
+        for_each_live(|site, _, _| if site.line() == line && site.file() == file!() { count += 1 });
+        count
+    };
+
+    let guard = track_site();
+    let line = line!() - 1;
+    let first = Box::new(1u64);
+    let second = Box::new(2u64);
+    drop(guard);
+    let untracked = Box::new(3u64);
+
+    assert_eq!(count_live(line), 2);
+    drop(first);
+    assert_eq!(count_live(line), 1);
+    drop((second, untracked));
+    assert_eq!(count_live(line), 0);
+}
@@ -0,0 +1,218 @@
+use spin::Mutex;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+use crate::acpi;
+use crate::memory::mmio::{map_mmio, CacheMode, MmioRegion};
+use crate::memory::vmalloc::VmallocError;
+
+// The MSR holding the local APIC's physical address and its global enable bit
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Local APIC registers (byte offsets into its MMIO page)
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_IN_SERVICE: usize = 0x100; // 8 registers of 32 vectors each, 0x10 apart
+const LAPIC_SPURIOUS: usize = 0xF0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+
+// I/O APIC registers, accessed through a select and a data window
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+// Where QEMU and most PCs put the I/O APIC, used when there's no MADT saying otherwise
+const DEFAULT_IO_APIC_BASE: u64 = 0xFEC0_0000;
+// Without a MADT the PIT's IRQ 0 is assumed to arrive on input 2, like it does nearly everywhere
+const DEFAULT_TIMER_GSI: u32 = 2;
+
+/// The vector the local APIC raises for spurious interrupts, it must not get an EOI
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The processor-local part of the APIC, which receives interrupts and takes the EOIs
+pub struct LocalApic {
+    region: MmioRegion,
+}
+
+impl LocalApic {
+    fn read(&self, register: usize) -> u32 {
+        self.region.read(register)
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        self.region.write(register, value)
+    }
+
+    /// The APIC ID of this processor, which is what I/O APIC entries are addressed to
+    pub fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    /// Signals the end of the interrupt currently being handled
+    pub fn end_of_interrupt(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+
+    /// Whether the APIC delivered the vector and is waiting for its EOI
+    pub fn in_service(&self, vector: u8) -> bool {
+        let register = LAPIC_IN_SERVICE + (vector as usize / 32) * 0x10;
+        self.read(register) & (1 << (vector % 32)) != 0
+    }
+}
+
+/// The I/O APIC, which routes device interrupts (GSIs) to local APICs
+pub struct IoApic {
+    region: MmioRegion,
+    gsi_base: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.region.write(IOAPIC_SELECT, register);
+        self.region.read(IOAPIC_WINDOW)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.region.write(IOAPIC_SELECT, register);
+        self.region.write(IOAPIC_WINDOW, value);
+    }
+
+    /// The number of inputs the I/O APIC has
+    pub fn inputs(&self) -> u32 {
+        ((self.read(IOAPIC_VERSION) >> 16) & 0xff) + 1
+    }
+
+    /// Whether the GSI is one of this I/O APIC's inputs
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.inputs()
+    }
+
+    fn write_redirection(&self, gsi: u32, entry: u64) {
+        assert!(self.handles(gsi), "GSI {} doesn't exist on this I/O APIC", gsi);
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // Mask first, so a half written entry never fires
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    /// Routes the input to the given vector on the local APIC with the given ID
+    ///
+    /// Uses fixed delivery, ISA IRQs are edge triggered and active high unless the MADT overrides them
+    pub fn route(&self, gsi: u32, vector: u8, apic_id: u8, active_low: bool, level_triggered: bool) {
+        let mut entry = (apic_id as u64) << 56 | vector as u64;
+        if active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if level_triggered {
+            entry |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        self.write_redirection(gsi, entry);
+    }
+
+    /// Stops the input from raising interrupts
+    pub fn mask(&self, gsi: u32) {
+        self.write_redirection(gsi, REDIRECTION_MASKED);
+    }
+}
+
+pub static LOCAL_APIC: Mutex<Option<LocalApic>> = Mutex::new(None);
+pub static IO_APIC: Mutex<Option<IoApic>> = Mutex::new(None);
+
+/// Whether the CPU has a local APIC (CPUID leaf 1, EDX bit 9)
+pub fn is_supported() -> bool {
+    unsafe { core::arch::x86_64::__cpuid(1) }.edx & (1 << 9) != 0
+}
+
+/// Maps and enables the local APIC and the I/O APIC, with every I/O APIC input masked
+///
+/// The 8259 PICs have to be masked before, see 'interrupts::init_controller'
+pub fn init() -> Result<(), VmallocError> {
+    let mut base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    base |= APIC_BASE_ENABLE;
+    unsafe { Msr::new(IA32_APIC_BASE).write(base) };
+
+    let local = LocalApic {
+        region: unsafe { map_mmio(PhysAddr::new(base & APIC_BASE_ADDR_MASK), 0x400, CacheMode::Uncached)? },
+    };
+    // Accept every priority, keep the timer quiet and turn the APIC on
+    local.write(LAPIC_TASK_PRIORITY, 0);
+    local.write(LAPIC_LVT_TIMER, LVT_MASKED);
+    local.write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+
+    // Only the I/O APIC handling the ISA IRQs is used, which is the only one on most machines
+    let (io_base, gsi_base) = acpi::madt()
+        .and_then(|madt| madt.io_apics.iter().find(|io| io.gsi_base == 0).map(|io| (io.address, io.gsi_base)))
+        .unwrap_or((PhysAddr::new(DEFAULT_IO_APIC_BASE), 0));
+    let io = IoApic {
+        region: unsafe { map_mmio(io_base, 0x20, CacheMode::Uncached)? },
+        gsi_base,
+    };
+    for gsi in io.gsi_base..io.gsi_base + io.inputs() {
+        io.mask(gsi);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *LOCAL_APIC.lock() = Some(local);
+        *IO_APIC.lock() = Some(io);
+    });
+    Ok(())
+}
+
+/// Routes an I/O APIC input to the given vector on this processor, edge triggered and active high
+///
+/// Panics if 'init' hasn't run
+pub fn route_irq(gsi: u32, vector: u8) {
+    route(gsi, vector, false, false);
+}
+
+/// Routes a legacy ISA IRQ to the given vector on this processor, following the MADT's overrides
+pub fn route_isa_irq(irq: u8, vector: u8) {
+    let (gsi, active_low, level_triggered) = isa_irq(irq);
+    route(gsi, vector, active_low, level_triggered);
+}
+
+/// Stops the I/O APIC input a legacy ISA IRQ arrives on from raising interrupts
+pub fn mask_isa_irq(irq: u8) {
+    let gsi = isa_irq(irq).0;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IO_APIC.lock().as_ref().expect("I/O APIC not initialized").mask(gsi);
+    });
+}
+
+// The GSI an ISA IRQ arrives on, and whether it's active low and level triggered
+fn isa_irq(irq: u8) -> (u32, bool, bool) {
+    match acpi::madt() {
+        Some(madt) => {
+            let (gsi, flags) = madt.isa_irq(irq);
+            let (active_low, level) = flags.map_or((false, false), |o| (o.active_low(), o.level_triggered()));
+            (gsi, active_low, level)
+        }
+        None if irq == 0 => (DEFAULT_TIMER_GSI, false, false),
+        None => (irq as u32, false, false),
+    }
+}
+
+fn route(gsi: u32, vector: u8, active_low: bool, level_triggered: bool) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let apic_id = LOCAL_APIC.lock().as_ref().expect("local APIC not initialized").id();
+        IO_APIC.lock().as_ref().expect("I/O APIC not initialized").route(gsi, vector, apic_id, active_low, level_triggered);
+    });
+}
+
+/// Whether the local APIC delivered the vector and is waiting for its EOI, false before 'init'
+pub fn in_service(vector: u8) -> bool {
+    LOCAL_APIC.lock().as_ref().is_some_and(|apic| apic.in_service(vector))
+}
+
+/// Sends the EOI for the interrupt currently being handled to the local APIC
+pub fn end_of_interrupt() {
+    LOCAL_APIC.lock().as_ref().expect("local APIC not initialized").end_of_interrupt();
+}
@@ -1,24 +1,27 @@
-use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
+use crate::memory::stack;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// Sizes (in pages) of the stacks the TSS points to
+const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
+const PRIVILEGE_STACK_PAGES: u64 = 5;
+
 lazy_static! {
     // Create a static reference to the TaskStateSegment
+    // Its stacks get mapped when it's created, so memory has to be set up before it's first used
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        // Get the address of the Double Fault Stack and write it to the 0th entry on the table
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            // This should be rewritten later to an actual stack allocation
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+        // Get a fresh stack for double faults and write its top to the 0th entry on the table
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack::allocate_stack(DOUBLE_FAULT_STACK_PAGES)
+            .expect("double fault stack allocation failed")
+            .top();
+        // The stack the CPU switches to when an interrupt arrives while running in ring 3
+        tss.privilege_stack_table[0] = stack::allocate_stack(PRIVILEGE_STACK_PAGES)
+            .expect("privilege stack allocation failed")
+            .top();
         tss
     };
 }
@@ -39,6 +42,7 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
+// Loads the GDT and TSS, needs 'rustos::init_memory' to have run first for the TSS stacks
 pub fn init() {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
@@ -1,10 +1,18 @@
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use pc_keyboard::ScancodeSet1;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use spin;
 use pic8259::ChainedPics;
-use crate::{hlt_loop, print, println};
-use crate::gdt;
+use crate::{print, println};
+use crate::memory;
+use crate::apic;
+
+pub mod exceptions;
+pub mod irq;
+pub mod deferred;
+pub mod stats;
+pub mod unhandled;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -12,61 +20,151 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Which interrupt controller delivers the hardware interrupts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Pic,  // The legacy 8259 pair
+    Apic, // The local APIC with an I/O APIC
+}
+
+// Set once 'init_controller' switched to the APIC, the EOIs go there from then on
+static USING_APIC: AtomicBool = AtomicBool::new(false);
+// Makes 'init_controller' keep the PICs even when there's an APIC
+static FORCE_PIC: AtomicBool = AtomicBool::new(false);
+
 // TODO Try to use raw handlers w this post: https://os.phil-opp.com/edition-1/extra/naked-exceptions/
 lazy_static! {
     // Create a static reference to the InterruptDescriptorTable that lives the duration of the program
    static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        // set the breakpoint handler to the function we made
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        // set the double fault handler to the function we made
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+        // Every CPU exception gets a handler, the fatal ones print a crash report
+        exceptions::set_handlers(&mut idt);
+        // So does every other vector, the handlers set after this replace these
+        unhandled::set_handlers(&mut idt);
 
-        // Set the page fault handler
+        // Set the page fault handler, which first tries to resolve the fault
         idt.page_fault.set_handler_fn(page_fault_handler);
 
-        // Set the timer handler (From the PIC)
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        // Set the keyboard handler (From the PIC)
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        // Hardware interrupts go through the handlers registered with 'irq::register'
+        irq::set_handlers(&mut idt);
+        // Spurious interrupts from the local APIC, these don't get an EOI
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
-// An enum to map each interrupt index to readable values
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard, // Defaults to Timer + 1
+/// The keyboard's IRQ line
+pub const KEYBOARD_IRQ: u8 = 1;
+
+// A method to load the IDT
+pub fn init_idt() {
+    IDT.load()
+}
+
+/// Makes 'init_controller' stay with the 8259 PICs, even if the CPU has an APIC
+pub fn force_pic() {
+    FORCE_PIC.store(true, Ordering::SeqCst);
+}
+
+/// Sets up the interrupt controller, the APIC if there is one and the 8259 PICs otherwise
+///
+/// The PICs are remapped either way, so anything they still raise lands on their own vectors
+/// instead of the CPU exceptions. Every line starts masked, registering a handler unmasks it.
+/// Needs the memory setup for mapping the APIC registers
+pub fn init_controller() -> Controller {
+    unsafe { PICS.lock().initialize() };
+    mask_pics();
+    if FORCE_PIC.load(Ordering::SeqCst) || !apic::is_supported() {
+        // The second PIC only gets through its cascade line
+        unmask_irq(2);
+        return Controller::Pic;
+    }
+    if let Err(err) = apic::init() {
+        println!("APIC setup failed ({:?}), staying with the PIC", err);
+        unmask_irq(2);
+        return Controller::Pic;
+    }
+
+    USING_APIC.store(true, Ordering::SeqCst);
+    Controller::Apic
+}
+
+/// Registers the keyboard handler, printing each key pressed
+pub fn init_keyboard() {
+    irq::register(KEYBOARD_IRQ, keyboard_irq_handler, core::ptr::null_mut()).expect("keyboard IRQ taken");
 }
 
-// Functions for easy numeric access to each interrupt index
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
+/// The interrupt controller in use
+pub fn controller() -> Controller {
+    if USING_APIC.load(Ordering::SeqCst) { Controller::Apic } else { Controller::Pic }
+}
+
+// Masks every line on both PICs
+fn mask_pics() {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
     }
+}
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+/// Lets the controller deliver the IRQ line, 'irq::register' does this for the first handler
+pub fn unmask_irq(irq: u8) {
+    match controller() {
+        Controller::Apic => apic::route_isa_irq(irq, irq::vector(irq)),
+        Controller::Pic => set_pic_mask(irq, false),
     }
 }
 
-// A method to load the IDT
-pub fn init_idt() {
-    IDT.load()
+/// Stops the controller from delivering the IRQ line
+pub fn mask_irq(irq: u8) {
+    match controller() {
+        Controller::Apic => apic::mask_isa_irq(irq),
+        Controller::Pic => set_pic_mask(irq, true),
+    }
 }
 
-// A function to handle breakpoint exceptions, just prints the exception currently
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+// Sets or clears the line's bit in the mask register of the PIC it belongs to
+fn set_pic_mask(irq: u8, masked: bool) {
+    use x86_64::instructions::port::Port;
+
+    let (mut data, bit) = match irq {
+        0..=7 => (Port::<u8>::new(0x21), irq),
+        _ => (Port::<u8>::new(0xa1), irq - 8),
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mask = data.read();
+        data.write(if masked { mask | 1 << bit } else { mask & !(1 << bit) });
+    });
+}
+
+/// Whether the IRQ is a spurious one from the 8259 PICs, which 'irq::register'ed handlers never see
+///
+/// A request that goes away before the CPU acknowledges it makes the PIC raise its lowest priority
+/// line, 7 or 15, without marking it in service. That interrupt must not get an EOI, as it would
+/// end another interrupt still in service
+pub fn is_spurious(irq: u8) -> bool {
+    use x86_64::instructions::port::Port;
+
+    if controller() == Controller::Apic || (irq != 7 && irq != 15) {
+        return false;
+    }
+    // OCW3 selects the in-service register for the next read of the command port
+    let mut command = Port::<u8>::new(if irq < 8 { 0x20 } else { 0xa0 });
+    let in_service = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        command.write(0x0b);
+        command.read()
+    });
+    in_service & 1 << (irq % 8) == 0
 }
 
-// A function to handle double fault exceptions, panics with exception stackframe currently
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+/// Signals the end of the IRQ to whichever controller raised it
+pub fn end_of_interrupt(irq: u8) {
+    match controller() {
+        Controller::Apic => apic::end_of_interrupt(),
+        Controller::Pic => unsafe { PICS.lock().notify_end_of_interrupt(irq::vector(irq)) },
+    }
 }
 
 extern "x86-interrupt" fn page_fault_handler(
@@ -74,25 +172,38 @@ extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode
 ) {
     use x86_64::registers::control::Cr2;
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
-
-// A function to handle timer interrupts, prints a '.' as of now
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
 
-    // Notify the PIC that we're finished processing the interrupt
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    stats::count(exceptions::Exception::PAGE_FAULT.vector);
+    // Kernel mappings made while another address space was active only need their level 4 entry copied
+    if memory::address_space::handle_page_fault(Cr2::read()) {
+        return;
+    }
+    // Writes to shared copy-on-write pages get a private copy
+    if memory::cow::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+    // First accesses to lazily backed memory get a fresh page and carry on
+    if memory::vma::handle_page_fault(Cr2::read(), error_code) {
+        return;
     }
+
+    let error_code = exceptions::ErrorCode::PageFault { flags: error_code, address: Cr2::read() };
+    exceptions::crash(exceptions::Exception::PAGE_FAULT, error_code, &stack_frame);
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+// Only reads the scancode, decoding and printing it is deferred so the handler stays short
+fn keyboard_irq_handler(_irq: u8, _context: *mut ()) -> bool {
     use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    // A full queue loses the key, which 'deferred::dropped' keeps count of
+    deferred::defer(handle_scancode, scancode as usize);
+    true
+}
+
+// Decodes the scancode and prints the key, runs with interrupts enabled
+fn handle_scancode(scancode: usize) {
     use spin::Mutex;
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet};
 
@@ -102,11 +213,9 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
         );
     }
 
+    // Only deferred work uses the keyboard state, and deferred work never runs nested
     let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+    if let Ok(Some(key_event)) = keyboard.add_byte(scancode as u8) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
                 DecodedKey::Unicode(character) => print!("{}", character),
@@ -115,10 +224,6 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
         }
     }
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
-
     // Number mappings on the keyboard (commended in favor of the 'pc-keyboard' crate)
     // let key = match scancode {
     //     0x02 => Some('1'),
@@ -138,6 +243,10 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     // }
 }
 
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    stats::count(apic::SPURIOUS_VECTOR);
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
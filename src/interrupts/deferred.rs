@@ -0,0 +1,239 @@
+// Deferred work: interrupt handlers queue up what doesn't have to happen right away, and it's run
+// with interrupts enabled once the handler is done, or by a kernel worker
+//
+// The queue is a bounded lock-free ring (Dmitry Vyukov's design): every slot carries a sequence
+// number saying whether it's free for the producer at that position or full for the consumer, so
+// handlers never wait for a lock the interrupted code might hold
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// How many work items can be waiting at once
+pub const QUEUE_SIZE: usize = 256;
+
+/// A function to run later, with the argument it was queued with
+#[derive(Debug, Clone, Copy)]
+pub struct WorkItem {
+    pub work: fn(usize),
+    pub argument: usize,
+}
+
+struct Slot {
+    // The sequence number minus the slot's index, so every slot can start out as 0
+    sequence: AtomicUsize,
+    item: UnsafeCell<Option<WorkItem>>,
+}
+
+struct WorkQueue {
+    slots: [Slot; QUEUE_SIZE],
+    enqueue_position: AtomicUsize,
+    dequeue_position: AtomicUsize,
+}
+
+// Each slot's item is only touched by whoever won its position, the sequence numbers hand it over
+unsafe impl Sync for WorkQueue {}
+
+impl WorkQueue {
+    const fn new() -> Self {
+        WorkQueue {
+            slots: [const { Slot { sequence: AtomicUsize::new(0), item: UnsafeCell::new(None) } }; QUEUE_SIZE],
+            enqueue_position: AtomicUsize::new(0),
+            dequeue_position: AtomicUsize::new(0),
+        }
+    }
+
+    fn sequence(&self, index: usize) -> usize {
+        self.slots[index].sequence.load(Ordering::Acquire).wrapping_add(index)
+    }
+
+    fn set_sequence(&self, index: usize, sequence: usize) {
+        self.slots[index].sequence.store(sequence.wrapping_sub(index), Ordering::Release);
+    }
+
+    fn push(&self, item: WorkItem) -> Result<(), WorkItem> {
+        let mut position = self.enqueue_position.load(Ordering::Relaxed);
+        loop {
+            let index = position % QUEUE_SIZE;
+            let difference = self.sequence(index) as isize - position as isize;
+            if difference == 0 {
+                // The slot is free, claim the position
+                match self.enqueue_position.compare_exchange_weak(position, position + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe { *self.slots[index].item.get() = Some(item) };
+                        self.set_sequence(index, position + 1);
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                }
+            } else if difference < 0 {
+                // The consumer hasn't emptied this slot since the last lap, so the queue is full
+                return Err(item);
+            } else {
+                position = self.enqueue_position.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        let position = self.dequeue_position.load(Ordering::Relaxed);
+        self.sequence(position % QUEUE_SIZE) != position + 1
+    }
+
+    fn pop(&self) -> Option<WorkItem> {
+        let mut position = self.dequeue_position.load(Ordering::Relaxed);
+        loop {
+            let index = position % QUEUE_SIZE;
+            let difference = self.sequence(index) as isize - (position + 1) as isize;
+            if difference == 0 {
+                // The slot is full, claim the position
+                match self.dequeue_position.compare_exchange_weak(position, position + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let item = unsafe { (*self.slots[index].item.get()).take() };
+                        self.set_sequence(index, position + QUEUE_SIZE);
+                        return item;
+                    }
+                    Err(current) => position = current,
+                }
+            } else if difference < 0 {
+                return None;
+            } else {
+                position = self.dequeue_position.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+static QUEUE: WorkQueue = WorkQueue::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+// Set while the queue is being drained, so work never runs nested in other work
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Queues the work to run later with interrupts enabled, safe to call from any interrupt handler
+///
+/// Returns false, and counts the item as dropped, if the queue is full
+pub fn defer(work: fn(usize), argument: usize) -> bool {
+    match QUEUE.push(WorkItem { work, argument }) {
+        Ok(()) => true,
+        Err(_) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+/// Runs queued work until the queue is empty, returns how many items ran
+///
+/// Does nothing if the queue is already being drained, an interrupt hitting the drain only queues its work
+pub fn run_pending() -> usize {
+    let mut count = 0;
+    while !DRAINING.swap(true, Ordering::Acquire) {
+        while let Some(item) = QUEUE.pop() {
+            (item.work)(item.argument);
+            count += 1;
+        }
+        DRAINING.store(false, Ordering::Release);
+        // Work queued after the last pop but before the flag was cleared would be stuck until the next drain
+        if QUEUE.is_empty() {
+            break;
+        }
+    }
+    count
+}
+
+/// The number of work items that didn't fit in the queue
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Called at the end of an IRQ, after the EOI, runs the queued work with interrupts enabled
+pub(super) fn run_on_irq_exit() {
+    if DRAINING.load(Ordering::Acquire) || QUEUE.is_empty() {
+        return;
+    }
+    x86_64::instructions::interrupts::enable();
+    run_pending();
+    x86_64::instructions::interrupts::disable();
+}
+
+/// Runs queued work forever, halting while there's none, for kernel code that has nothing else to do
+pub fn worker_loop() -> ! {
+    loop {
+        run_pending();
+        // Anything queued between the check and the halt is run when that interrupt returns
+        x86_64::instructions::hlt();
+    }
+}
+
+// Test that work runs in the order it was queued, and only when drained
+#[test_case]
+fn test_work_order() {
+    static RESULT: AtomicUsize = AtomicUsize::new(0);
+    fn append(digit: usize) {
+        RESULT.store(RESULT.load(Ordering::SeqCst) * 10 + digit, Ordering::SeqCst);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for digit in 1..=3 {
+            assert!(defer(append, digit));
+        }
+        assert_eq!(RESULT.load(Ordering::SeqCst), 0);
+        assert_eq!(run_pending(), 3);
+    });
+    assert_eq!(RESULT.load(Ordering::SeqCst), 123);
+}
+
+// Test that a full queue refuses work instead of overwriting it, and takes more once drained
+#[test_case]
+fn test_queue_full() {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    fn count(_: usize) {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        run_pending();
+        let dropped_before = dropped();
+        // Go around the ring more than once, so the sequence numbers wrap
+        for _ in 0..3 {
+            RUNS.store(0, Ordering::SeqCst);
+            for _ in 0..QUEUE_SIZE {
+                assert!(defer(count, 0));
+            }
+            assert!(!defer(count, 0));
+            assert_eq!(run_pending(), QUEUE_SIZE);
+            assert_eq!(RUNS.load(Ordering::SeqCst), QUEUE_SIZE);
+        }
+        assert_eq!(dropped(), dropped_before + 3);
+    });
+}
+
+// Test that work an IRQ handler defers runs once the handler is done, exactly once and with interrupts enabled
+#[test_case]
+fn test_runs_on_irq_exit() {
+    use core::sync::atomic::AtomicBool;
+    use x86_64::instructions::interrupts;
+    use super::irq;
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static INTERRUPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+    fn work(_: usize) {
+        INTERRUPTS_ENABLED.store(interrupts::are_enabled(), Ordering::SeqCst);
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+    fn handler(_irq: u8, _context: *mut ()) -> bool {
+        assert!(defer(work, 0));
+        true
+    }
+
+    // IRQ 5 has no device under QEMU, so the interrupt is raised by hand, with interrupts off like in a real one
+    let id = irq::register(5, handler, core::ptr::null_mut()).unwrap();
+    interrupts::without_interrupts(|| {
+        irq::dispatch(5);
+        assert!(!interrupts::are_enabled());
+    });
+    irq::unregister(id);
+
+    assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    assert!(INTERRUPTS_ENABLED.load(Ordering::SeqCst));
+    assert_eq!(run_pending(), 0);
+}
@@ -0,0 +1,359 @@
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use super::stats;
+use crate::gdt;
+use crate::hlt_loop;
+
+/// An architecturally defined CPU exception
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exception {
+    pub vector: u8,
+    pub mnemonic: &'static str,
+    pub name: &'static str,
+}
+
+impl Exception {
+    pub const DIVIDE_ERROR: Exception = Exception { vector: 0, mnemonic: "#DE", name: "DIVIDE ERROR" };
+    pub const DEBUG: Exception = Exception { vector: 1, mnemonic: "#DB", name: "DEBUG" };
+    pub const NON_MASKABLE_INTERRUPT: Exception = Exception { vector: 2, mnemonic: "NMI", name: "NON-MASKABLE INTERRUPT" };
+    pub const BREAKPOINT: Exception = Exception { vector: 3, mnemonic: "#BP", name: "BREAKPOINT" };
+    pub const OVERFLOW: Exception = Exception { vector: 4, mnemonic: "#OF", name: "OVERFLOW" };
+    pub const BOUND_RANGE_EXCEEDED: Exception = Exception { vector: 5, mnemonic: "#BR", name: "BOUND RANGE EXCEEDED" };
+    pub const INVALID_OPCODE: Exception = Exception { vector: 6, mnemonic: "#UD", name: "INVALID OPCODE" };
+    pub const DEVICE_NOT_AVAILABLE: Exception = Exception { vector: 7, mnemonic: "#NM", name: "DEVICE NOT AVAILABLE" };
+    pub const DOUBLE_FAULT: Exception = Exception { vector: 8, mnemonic: "#DF", name: "DOUBLE FAULT" };
+    pub const INVALID_TSS: Exception = Exception { vector: 10, mnemonic: "#TS", name: "INVALID TSS" };
+    pub const SEGMENT_NOT_PRESENT: Exception = Exception { vector: 11, mnemonic: "#NP", name: "SEGMENT NOT PRESENT" };
+    pub const STACK_SEGMENT_FAULT: Exception = Exception { vector: 12, mnemonic: "#SS", name: "STACK SEGMENT FAULT" };
+    pub const GENERAL_PROTECTION_FAULT: Exception = Exception { vector: 13, mnemonic: "#GP", name: "GENERAL PROTECTION FAULT" };
+    pub const PAGE_FAULT: Exception = Exception { vector: 14, mnemonic: "#PF", name: "PAGE FAULT" };
+    pub const X87_FLOATING_POINT: Exception = Exception { vector: 16, mnemonic: "#MF", name: "X87 FLOATING POINT" };
+    pub const ALIGNMENT_CHECK: Exception = Exception { vector: 17, mnemonic: "#AC", name: "ALIGNMENT CHECK" };
+    pub const MACHINE_CHECK: Exception = Exception { vector: 18, mnemonic: "#MC", name: "MACHINE CHECK" };
+    pub const SIMD_FLOATING_POINT: Exception = Exception { vector: 19, mnemonic: "#XM", name: "SIMD FLOATING POINT" };
+    pub const VIRTUALIZATION: Exception = Exception { vector: 20, mnemonic: "#VE", name: "VIRTUALIZATION" };
+    pub const CONTROL_PROTECTION: Exception = Exception { vector: 21, mnemonic: "#CP", name: "CONTROL PROTECTION" };
+    pub const HYPERVISOR_INJECTION: Exception = Exception { vector: 28, mnemonic: "#HV", name: "HYPERVISOR INJECTION" };
+    pub const VMM_COMMUNICATION: Exception = Exception { vector: 29, mnemonic: "#VC", name: "VMM COMMUNICATION" };
+    pub const SECURITY: Exception = Exception { vector: 30, mnemonic: "#SX", name: "SECURITY" };
+
+    const ALL: [Exception; 23] = [
+        Self::DIVIDE_ERROR, Self::DEBUG, Self::NON_MASKABLE_INTERRUPT, Self::BREAKPOINT, Self::OVERFLOW,
+        Self::BOUND_RANGE_EXCEEDED, Self::INVALID_OPCODE, Self::DEVICE_NOT_AVAILABLE, Self::DOUBLE_FAULT,
+        Self::INVALID_TSS, Self::SEGMENT_NOT_PRESENT, Self::STACK_SEGMENT_FAULT,
+        Self::GENERAL_PROTECTION_FAULT, Self::PAGE_FAULT, Self::X87_FLOATING_POINT, Self::ALIGNMENT_CHECK,
+        Self::MACHINE_CHECK, Self::SIMD_FLOATING_POINT, Self::VIRTUALIZATION, Self::CONTROL_PROTECTION,
+        Self::HYPERVISOR_INJECTION, Self::VMM_COMMUNICATION, Self::SECURITY,
+    ];
+
+    /// The exception raised on the vector, None for reserved vectors and interrupts
+    pub fn from_vector(vector: u8) -> Option<Exception> {
+        Self::ALL.iter().copied().find(|exception| exception.vector == vector)
+    }
+}
+
+/// The error code an exception pushed, decoded by the kind of exception
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    None,
+    /// Refers to the segment selector that caused the fault, or 0 if it wasn't about a selector
+    Selector { external: bool, table: SelectorTable, index: u16 },
+    PageFault { flags: PageFaultErrorCode, address: VirtAddr },
+    /// The kind of control flow violation behind a #CP, and whether it happened inside an SGX enclave
+    ControlProtection { cause: u16, enclave: bool },
+    /// The SVM exit code of the instruction or event a #VC intercepted
+    VmmCommunication(u64),
+    Raw(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl ErrorCode {
+    /// Decodes the error code of #TS, #NP, #SS and #GP
+    pub fn selector(code: u64) -> Self {
+        let table = match (code >> 1) & 0b11 {
+            0b00 => SelectorTable::Gdt,
+            0b10 => SelectorTable::Ldt,
+            _ => SelectorTable::Idt,
+        };
+        ErrorCode::Selector { external: code & 1 != 0, table, index: (code >> 3) as u16 & 0x1fff }
+    }
+
+    /// Decodes the error code of #CP
+    pub fn control_protection(code: u64) -> Self {
+        ErrorCode::ControlProtection { cause: code as u16 & 0x7fff, enclave: code & 1 << 15 != 0 }
+    }
+}
+
+// What the shadow stack or indirect branch tracking caught
+fn control_protection_cause(cause: u16) -> &'static str {
+    match cause {
+        1 => "near return",
+        2 => "far return or iret",
+        3 => "missing endbranch",
+        4 => "rstorssp",
+        5 => "setssbsy",
+        _ => "unknown cause",
+    }
+}
+
+// The event behind the most common #VC exit codes, as the hypervisor would have seen it
+fn vmm_exit_name(exit_code: u64) -> &'static str {
+    match exit_code {
+        0x6e => "rdtsc",
+        0x6f => "rdpmc",
+        0x72 => "cpuid",
+        0x76 => "invd",
+        0x7b => "port I/O",
+        0x7c => "msr access",
+        0x81 => "vmmcall",
+        0x87 => "rdtscp",
+        0x89 => "wbinvd",
+        0x400 => "nested page fault",
+        _ => "other exit",
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::None => write!(f, "none"),
+            ErrorCode::Selector { external: false, table: SelectorTable::Gdt, index: 0 } => write!(f, "0 (not caused by a selector)"),
+            ErrorCode::Selector { external, table, index } => {
+                write!(f, "{:?} entry {}{}", table, index, if *external { ", external event" } else { "" })
+            }
+            ErrorCode::PageFault { flags, address } => write!(f, "{:?} accessing {:#x}", flags, address.as_u64()),
+            ErrorCode::ControlProtection { cause, enclave } => {
+                write!(f, "{} ({}){}", cause, control_protection_cause(*cause), if *enclave { ", in an enclave" } else { "" })
+            }
+            ErrorCode::VmmCommunication(exit_code) => write!(f, "exit code {:#x} ({})", exit_code, vmm_exit_name(*exit_code)),
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+/// Everything the kernel knows about an exception, printed when it can't be handled
+pub struct CrashReport<'a> {
+    pub exception: Exception,
+    pub error_code: ErrorCode,
+    pub stack_frame: &'a InterruptStackFrame,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl<'a> CrashReport<'a> {
+    fn new(exception: Exception, error_code: ErrorCode, stack_frame: &'a InterruptStackFrame) -> Self {
+        CrashReport {
+            exception,
+            error_code,
+            stack_frame,
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: Cr3::read().0.start_address().as_u64() | Cr3::read().1.bits(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for CrashReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "EXCEPTION: {} ({}, vector {})", self.exception.name, self.exception.mnemonic, self.exception.vector)?;
+        writeln!(f, "Error Code: {}", self.error_code)?;
+        writeln!(f, "CR0={:#x} CR2={:#x} CR3={:#x} CR4={:#x}", self.cr0, self.cr2, self.cr3, self.cr4)?;
+        write!(f, "{:#?}", self.stack_frame)
+    }
+}
+
+// Called with the report before the kernel halts, lets tests check what crashed
+static CRASH_HOOK: Mutex<Option<fn(&CrashReport)>> = Mutex::new(None);
+
+/// Sets a function to call with the crash report of every fatal exception, before halting
+pub fn set_crash_hook(hook: fn(&CrashReport)) {
+    x86_64::instructions::interrupts::without_interrupts(|| *CRASH_HOOK.lock() = Some(hook));
+}
+
+/// Prints the report to the VGA buffer and the serial port
+pub fn print_report(report: &CrashReport) {
+    // The exception may have hit while either was locked, and that code won't run again
+    unsafe {
+        crate::vga_buffer::WRITER.force_unlock();
+        crate::serial::SERIAL1.force_unlock();
+    }
+    let _ = writeln!(crate::vga_buffer::WRITER.lock(), "{}", report);
+    let _ = writeln!(crate::serial::SERIAL1.lock(), "{}", report);
+}
+
+/// Reports an exception the kernel can't recover from and halts
+pub fn crash(exception: Exception, error_code: ErrorCode, stack_frame: &InterruptStackFrame) -> ! {
+    // Page faults count themselves, as most of them are resolved and never get here
+    if exception != Exception::PAGE_FAULT {
+        stats::count(exception.vector);
+    }
+    let report = CrashReport::new(exception, error_code, stack_frame);
+    print_report(&report);
+    let hook = *CRASH_HOOK.lock();
+    if let Some(hook) = hook {
+        hook(&report);
+    }
+    hlt_loop();
+}
+
+/// Points every exception but the page fault at the handlers here
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    // A double fault often comes from a stack overflow, so it gets its own stack
+    unsafe {
+        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception.set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception.set_handler_fn(hypervisor_injection_handler);
+    idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+}
+
+// Debug traps, breakpoints and NMIs are reported, and execution carries on after them
+fn report(exception: Exception, stack_frame: &InterruptStackFrame) {
+    stats::count(exception.vector);
+    crate::println!("EXCEPTION: {}\n{:#?}", exception.name, stack_frame);
+    crate::serial_println!("EXCEPTION: {}\n{:#?}", exception.name, stack_frame);
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    crash(Exception::DIVIDE_ERROR, ErrorCode::None, &stack_frame);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    report(Exception::DEBUG, &stack_frame);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    report(Exception::NON_MASKABLE_INTERRUPT, &stack_frame);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    report(Exception::BREAKPOINT, &stack_frame);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    crash(Exception::OVERFLOW, ErrorCode::None, &stack_frame);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    crash(Exception::BOUND_RANGE_EXCEEDED, ErrorCode::None, &stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    crash(Exception::INVALID_OPCODE, ErrorCode::None, &stack_frame);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    crash(Exception::DEVICE_NOT_AVAILABLE, ErrorCode::None, &stack_frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    crash(Exception::DOUBLE_FAULT, ErrorCode::Raw(error_code), &stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    crash(Exception::INVALID_TSS, ErrorCode::selector(error_code), &stack_frame);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    crash(Exception::SEGMENT_NOT_PRESENT, ErrorCode::selector(error_code), &stack_frame);
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    crash(Exception::STACK_SEGMENT_FAULT, ErrorCode::selector(error_code), &stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    crash(Exception::GENERAL_PROTECTION_FAULT, ErrorCode::selector(error_code), &stack_frame);
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    crash(Exception::X87_FLOATING_POINT, ErrorCode::None, &stack_frame);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    crash(Exception::ALIGNMENT_CHECK, ErrorCode::Raw(error_code), &stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    crash(Exception::MACHINE_CHECK, ErrorCode::None, &stack_frame);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    crash(Exception::SIMD_FLOATING_POINT, ErrorCode::None, &stack_frame);
+}
+
+extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
+    crash(Exception::VIRTUALIZATION, ErrorCode::None, &stack_frame);
+}
+
+extern "x86-interrupt" fn control_protection_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    crash(Exception::CONTROL_PROTECTION, ErrorCode::control_protection(error_code), &stack_frame);
+}
+
+// Only raised to guests of a hypervisor that uses it to inject events, which this kernel doesn't expect
+extern "x86-interrupt" fn hypervisor_injection_handler(stack_frame: InterruptStackFrame) {
+    crash(Exception::HYPERVISOR_INJECTION, ErrorCode::None, &stack_frame);
+}
+
+// Raised inside SEV-ES guests for events the hypervisor can't see, which would need a GHCB protocol to handle
+extern "x86-interrupt" fn vmm_communication_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    crash(Exception::VMM_COMMUNICATION, ErrorCode::VmmCommunication(error_code), &stack_frame);
+}
+
+extern "x86-interrupt" fn security_exception_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    crash(Exception::SECURITY, ErrorCode::Raw(error_code), &stack_frame);
+}
+
+// Test that selector error codes are split into their fields
+#[test_case]
+fn test_selector_error_code() {
+    assert_eq!(ErrorCode::selector(0), ErrorCode::Selector { external: false, table: SelectorTable::Gdt, index: 0 });
+    // IDT entry 13, raised by an external event
+    assert_eq!(ErrorCode::selector(13 << 3 | 0b011), ErrorCode::Selector { external: true, table: SelectorTable::Idt, index: 13 });
+    assert_eq!(ErrorCode::selector(5 << 3 | 0b100), ErrorCode::Selector { external: false, table: SelectorTable::Ldt, index: 5 });
+
+    let mut text = alloc::string::String::new();
+    write!(text, "{}", ErrorCode::selector(0x18)).unwrap();
+    assert_eq!(text, "Gdt entry 3");
+}
+
+// Test that #CP and #VC error codes are decoded
+#[test_case]
+fn test_control_protection_error_code() {
+    assert_eq!(ErrorCode::control_protection(3), ErrorCode::ControlProtection { cause: 3, enclave: false });
+    assert_eq!(ErrorCode::control_protection(1 << 15 | 1), ErrorCode::ControlProtection { cause: 1, enclave: true });
+
+    let mut text = alloc::string::String::new();
+    write!(text, "{} / {}", ErrorCode::control_protection(3), ErrorCode::VmmCommunication(0x72)).unwrap();
+    assert_eq!(text, "3 (missing endbranch) / exit code 0x72 (cpuid)");
+}
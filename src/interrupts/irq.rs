@@ -0,0 +1,154 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use super::{deferred, end_of_interrupt, is_spurious, mask_irq, stats, unmask_irq, PIC_1_OFFSET};
+
+/// The number of IRQ lines, the ISA ones both interrupt controllers know about
+pub const IRQ_LINES: usize = 16;
+
+/// How many handlers can share one IRQ line
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+/// A function handling an IRQ, called with the line and the context pointer it was registered with
+///
+/// Returns whether its device raised the interrupt, handlers sharing a line are all called anyway
+pub type IrqHandler = fn(irq: u8, context: *mut ()) -> bool;
+
+/// Identifies a registered handler, for unregistering it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq(u8),
+    LineFull(u8), // 'MAX_SHARED_HANDLERS' are already registered for the line
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registration {
+    id: HandlerId,
+    handler: IrqHandler,
+    context: usize, // The context pointer, kept as an address so the table can be shared
+}
+
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(1);
+
+// The handlers of each line, in a fixed table as dispatching can't use the heap
+static HANDLERS: Mutex<[[Option<Registration>; MAX_SHARED_HANDLERS]; IRQ_LINES]> =
+    Mutex::new([[None; MAX_SHARED_HANDLERS]; IRQ_LINES]);
+
+/// The IDT vector an IRQ line arrives on
+pub fn vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// Calls the handler for every interrupt on the line, and unmasks the line if it's the first one
+///
+/// The context pointer is passed to the handler as is, it has to stay valid until the handler is unregistered
+pub fn register(irq: u8, handler: IrqHandler, context: *mut ()) -> Result<HandlerId, IrqError> {
+    if irq as usize >= IRQ_LINES {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    let id = HandlerId(NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed));
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[irq as usize];
+        let first = line.iter().all(Option::is_none);
+        let slot = line.iter_mut().find(|slot| slot.is_none()).ok_or(IrqError::LineFull(irq))?;
+        *slot = Some(Registration { id, handler, context: context as usize });
+        if first {
+            unmask_irq(irq);
+        }
+        Ok(id)
+    })
+}
+
+/// Removes the handler, masking its line if no other handler is left on it
+///
+/// Returns false if it wasn't registered
+pub fn unregister(id: HandlerId) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        for (irq, line) in handlers.iter_mut().enumerate() {
+            if let Some(slot) = line.iter_mut().find(|slot| slot.is_some_and(|r| r.id == id)) {
+                *slot = None;
+                if line.iter().all(Option::is_none) {
+                    mask_irq(irq as u8);
+                }
+                return true;
+            }
+        }
+        false
+    })
+}
+
+// Runs every handler registered for the line, sends the EOI and then runs the work they deferred
+pub(super) fn dispatch(irq: u8) {
+    if is_spurious(irq) {
+        stats::count_spurious(irq);
+        // The slave's spurious IRQ 15 came in through the master's cascade line, which is in service
+        if irq == 15 {
+            end_of_interrupt(2);
+        }
+        return;
+    }
+    stats::count(vector(irq));
+
+    // Copy the line out, so handlers are free to register and unregister
+    let line = HANDLERS.lock()[irq as usize];
+    for registration in line.iter().flatten() {
+        (registration.handler)(irq, registration.context as *mut ());
+    }
+    end_of_interrupt(irq);
+    deferred::run_on_irq_exit();
+}
+
+/// Points the vectors of all IRQ lines at the common dispatch
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    for (irq, stub) in STUBS.iter().enumerate() {
+        idt[vector(irq as u8) as usize].set_handler_fn(*stub);
+    }
+}
+
+// The CPU doesn't tell a handler which vector it was called for, so each line gets its own entry point
+macro_rules! irq_stubs {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+        const STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [$($name),*];
+    };
+}
+
+irq_stubs! {
+    0 => irq_0, 1 => irq_1, 2 => irq_2, 3 => irq_3, 4 => irq_4, 5 => irq_5, 6 => irq_6, 7 => irq_7,
+    8 => irq_8, 9 => irq_9, 10 => irq_10, 11 => irq_11, 12 => irq_12, 13 => irq_13, 14 => irq_14, 15 => irq_15,
+}
+
+// Test that handlers sharing a line each get their context, and the line is free again after unregistering
+#[test_case]
+fn test_shared_line() {
+    use core::sync::atomic::AtomicUsize;
+
+    fn count(_irq: u8, context: *mut ()) -> bool {
+        unsafe { &*(context as *const AtomicUsize) }.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+    static FIRST: AtomicUsize = AtomicUsize::new(0);
+    static SECOND: AtomicUsize = AtomicUsize::new(0);
+
+    // IRQ 5 has no device under QEMU, so the interrupt is raised by hand
+    let first = register(5, count, &FIRST as *const _ as *mut ()).unwrap();
+    let second = register(5, count, &SECOND as *const _ as *mut ()).unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| dispatch(5));
+    assert_eq!((FIRST.load(Ordering::SeqCst), SECOND.load(Ordering::SeqCst)), (1, 1));
+
+    assert!(unregister(first));
+    assert!(!unregister(first));
+    x86_64::instructions::interrupts::without_interrupts(|| dispatch(5));
+    assert_eq!((FIRST.load(Ordering::SeqCst), SECOND.load(Ordering::SeqCst)), (1, 2));
+    assert!(unregister(second));
+    assert_eq!(register(IRQ_LINES as u8, count, core::ptr::null_mut()), Err(IrqError::InvalidIrq(16)));
+}
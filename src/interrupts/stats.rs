@@ -0,0 +1,88 @@
+// Per-vector interrupt counters, dumped as a table much like Linux's /proc/interrupts
+//
+// Every handler counts itself, so a device that stopped interrupting or a storm on one line
+// shows up without having to attach a debugger
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::exceptions::Exception;
+use super::irq::{self, IRQ_LINES};
+use crate::apic;
+
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+// Spurious 8259 interrupts on IRQ 7 and 15, they never reach a handler so aren't counted per vector
+static SPURIOUS: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+
+/// Counts an interrupt on the vector, called by its handler
+pub fn count(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a spurious interrupt from the PICs, 'irq' being 7 or 15
+pub(super) fn count_spurious(irq: u8) {
+    SPURIOUS[(irq >= 8) as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How many interrupts arrived on the vector since boot
+pub fn interrupts(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// How many spurious interrupts the PICs raised on the line since boot, always 0 for lines but 7 and 15
+pub fn spurious(irq: u8) -> u64 {
+    match irq {
+        7 => SPURIOUS[0].load(Ordering::Relaxed),
+        15 => SPURIOUS[1].load(Ordering::Relaxed),
+        _ => 0,
+    }
+}
+
+// What raises the vector, for the last column of the table
+fn source(vector: u8, f: &mut dyn Write) -> fmt::Result {
+    let first_irq = irq::vector(0);
+    if let Some(exception) = Exception::from_vector(vector) {
+        write!(f, "{} {}", exception.mnemonic, exception.name)
+    } else if (first_irq..first_irq + IRQ_LINES as u8).contains(&vector) {
+        write!(f, "IRQ {}", vector - first_irq)
+    } else if vector == apic::SPURIOUS_VECTOR {
+        write!(f, "APIC spurious")
+    } else {
+        write!(f, "unhandled")
+    }
+}
+
+/// Writes a line for every vector that was raised, then the spurious PIC interrupts
+pub fn write_table(f: &mut dyn Write) -> fmt::Result {
+    writeln!(f, "vector       count  source")?;
+    for vector in 0..=255u8 {
+        let count = interrupts(vector);
+        if count == 0 {
+            continue;
+        }
+        write!(f, "{:>6} {:>11}  ", vector, count)?;
+        source(vector, f)?;
+        writeln!(f)?;
+    }
+    writeln!(f, "   SPU {:>11}  spurious IRQ 7 and 15", spurious(7) + spurious(15))
+}
+
+/// Prints the counters to the screen
+pub fn dump() {
+    // Formatted up front, as interrupts that arrive while printing would change the counts halfway
+    let mut table = alloc::string::String::new();
+    let _ = write_table(&mut table);
+    crate::print!("{}", table);
+}
+
+// Test that exceptions are counted and show up in the table
+#[test_case]
+fn test_breakpoint_counted() {
+    let before = interrupts(Exception::BREAKPOINT.vector);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(interrupts(Exception::BREAKPOINT.vector), before + 1);
+
+    let mut table = alloc::string::String::new();
+    write_table(&mut table).unwrap();
+    assert!(table.lines().any(|line| line.trim_start().starts_with("3 ") && line.ends_with("#BP BREAKPOINT")));
+    assert!(table.lines().last().unwrap().trim_start().starts_with("SPU"));
+}
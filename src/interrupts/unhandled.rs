@@ -0,0 +1,71 @@
+// Default handlers for every vector without one of its own, so a stray interrupt is logged and
+// counted instead of raising a general protection fault for the missing IDT entry
+//
+// Every architecturally defined exception has its handler in exceptions.rs. Of the rest below 32,
+// vector 9 (the coprocessor segment overrun only 386s raise) gets a default handler here, while
+// the reserved vectors 15, 22 to 27 and 31 can't be set through the 'x86_64' crate
+
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use super::{controller, deferred, stats, Controller};
+use crate::apic;
+
+/// Points vector 9 and every vector from 32 up at a handler logging it, before the real handlers are set
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt[9].set_handler_fn(unhandled_interrupt_handler::<9>);
+    for (index, handler) in HANDLERS.iter().enumerate() {
+        idt[index + 32].set_handler_fn(*handler);
+    }
+}
+
+// The CPU doesn't tell a handler which vector it was called for, so each one gets its own instance
+extern "x86-interrupt" fn unhandled_interrupt_handler<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    handle(VECTOR);
+}
+
+fn handle(vector: u8) {
+    stats::count(vector);
+    // A device routed through the APIC needs the EOI, or it blocks every vector of lower priority.
+    // Software interrupts never set the in-service bit, and an EOI for those would end another interrupt
+    if controller() == Controller::Apic && apic::in_service(vector) {
+        apic::end_of_interrupt();
+    }
+    // Printing here could deadlock on a writer the interrupted code holds
+    deferred::defer(log_unhandled, vector as usize);
+    deferred::run_on_irq_exit();
+}
+
+fn log_unhandled(vector: usize) {
+    crate::println!("unhandled interrupt on vector {} ({:#x})", vector, vector);
+}
+
+macro_rules! default_handlers {
+    ($($vector:literal,)*) => {
+        const HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); 224] = [$(unhandled_interrupt_handler::<$vector>),*];
+    };
+}
+
+default_handlers! {
+    32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47,
+    48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+    64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79,
+    80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95,
+    96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111,
+    112, 113, 114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127,
+    128, 129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139, 140, 141, 142, 143,
+    144, 145, 146, 147, 148, 149, 150, 151, 152, 153, 154, 155, 156, 157, 158, 159,
+    160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 170, 171, 172, 173, 174, 175,
+    176, 177, 178, 179, 180, 181, 182, 183, 184, 185, 186, 187, 188, 189, 190, 191,
+    192, 193, 194, 195, 196, 197, 198, 199, 200, 201, 202, 203, 204, 205, 206, 207,
+    208, 209, 210, 211, 212, 213, 214, 215, 216, 217, 218, 219, 220, 221, 222, 223,
+    224, 225, 226, 227, 228, 229, 230, 231, 232, 233, 234, 235, 236, 237, 238, 239,
+    240, 241, 242, 243, 244, 245, 246, 247, 248, 249, 250, 251, 252, 253, 254, 255,
+}
+
+// Test that an unhandled vector is counted
+#[test_case]
+fn test_unhandled_vector() {
+    // Vector 0x80 has no handler, and no device raises it
+    let before = stats::interrupts(0x80);
+    x86_64::instructions::interrupts::without_interrupts(|| handle(0x80));
+    assert_eq!(stats::interrupts(0x80), before + 1);
+}
@@ -2,17 +2,27 @@
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
 
+extern crate alloc;
+
 use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
+use x86_64::VirtAddr;
+use memory::bitmap::BitmapFrameAllocator;
 
 pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
 pub mod gdt;
 pub mod memory;
+pub mod allocator;
+pub mod apic;
+pub mod acpi;
+pub mod power;
+pub mod time;
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
@@ -37,6 +47,8 @@ impl<T> Testable for T where T: Fn() {
 // '&[&dyn Fn()] - Slice of items that implement the 'Fn()' trait (basically a list of references to functions)
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
+    // A fatal exception fails the test like a panic, instead of halting until the runner times out
+    interrupts::exceptions::set_crash_hook(test_crash_hook);
     // Run each test
     for test in tests {
         test.run();
@@ -53,20 +65,50 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     loop {}
 }
 
+// Called after the crash report was printed (to serial too), a test can't carry on after it
+fn test_crash_hook(_report: &interrupts::exceptions::CrashReport) {
+    serial_println!("[failed]\n");
+    exit_qemu(QemuExitCode::Failed);
+}
+
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init_memory(boot_info);
     init();
     test_main();
     hlt_loop();
 }
 
+// Sets up the GDT, interrupts and the interrupt controller, needs 'init_memory' to have run first
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
-    unsafe { interrupts::PICS.lock().initialize() }; // Initialize PICs (unsafe as it can cause undefined behaviour when PIC is misconfigured)
+    if let Err(err) = acpi::init() {
+        println!("ACPI tables not usable: {:?}", err);
+    }
+    interrupts::init_controller(); // The APIC if there is one, the PICs otherwise
+    time::init();
+    interrupts::init_keyboard();
     x86_64::instructions::interrupts::enable(); // Enable interrupts
 }
 
+// Sets up paging, the frame allocator and the kernel heap from the bootloader's memory info
+pub fn init_memory(boot_info: &'static BootInfo) {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    memory::protection::enable_nxe();
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    // Hand both over to the rest of the kernel
+    *memory::MAPPER.lock() = Some(mapper);
+    *memory::FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    memory::mmio::init_pat();
+    memory::cow::init();
+    memory::buddy::init();
+    memory::protection::enforce_wx(&boot_info.memory_map);
+}
+
 // A loop that sends CPU halt instructions when not needed
 pub fn hlt_loop() -> ! {
     loop {
@@ -80,6 +122,12 @@ fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
+// Called when a heap allocation fails (e.g. the heap is out of memory)
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!("allocation error: {:?}", layout)
+}
+
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)] // Represented as a u32
@@ -89,6 +137,8 @@ pub enum QemuExitCode {
 }
 
 // A method to exit qemu, uses the x86_64 crate to write an exit code to the 0xf4 port and shutdown the emulator
+// Only the tests use it, as the exit code is what tells the runner how they went. Everything else
+// turns the machine off with 'power::shutdown'
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
@@ -4,22 +4,30 @@
 #![test_runner(rustos::test_runner)] // Use the shared library's method as the test runner
 #![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
 
+extern crate alloc;
+
 use core::panic::PanicInfo;
+use alloc::{boxed::Box, vec::Vec};
 use bootloader::{BootInfo, entry_point};
-use x86_64::structures::paging::Translate;
-use rustos::{memory, println};
-use rustos::memory::BootInfoFrameAllocator;
+use rustos::println;
 
 // The bootloader package's provided macro to set the entry point of the OS
 entry_point!(kernel_main);
 
 // Rust type-checked entry function with the 'boot_info' parameter
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    use x86_64::{structures::paging::Page, VirtAddr};
-
     println!("Hello World{}", "!");
+
+    // Set up paging and the kernel heap so the 'alloc' crate (and the kernel stacks) can be used
+    rustos::init_memory(boot_info);
     rustos::init();
 
+    // Quick sanity check that heap allocations work
+    let heap_value = Box::new(41);
+    println!("heap_value at {:p}", heap_value);
+    let vec: Vec<u64> = (0..500).collect();
+    println!("vec at {:p}", vec.as_slice());
+
     #[cfg(test)]
     test_main(); // Call that renamed function on testing configs
 
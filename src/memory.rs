@@ -1,58 +1,92 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
     PhysAddr,
-    structures::paging::{PageTable, Page, PhysFrame, Mapper, Size4KiB, FrameAllocator},
+    structures::paging::{PageTable, PhysFrame},
     VirtAddr
 };
 use x86_64::structures::paging::OffsetPageTable;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use bitmap::BitmapFrameAllocator;
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
+pub mod bitmap;
+pub mod slab;
+pub mod buddy;
+pub mod vma;
+pub mod stack;
+pub mod vmalloc;
+pub mod mmio;
+pub mod address_space;
+pub mod cow;
+pub mod huge;
+pub mod protection;
+pub mod inspect;
+pub mod stats;
 
-impl BootInfoFrameAllocator {
-    /// A convenience method to create a FrameAllocator from the passed memory map
-    ///
-    /// Unsafe as the caller must guarantee that the passed map is valid
-    /// To be valid, all frames marked 'USABLE' must truly be usable
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0
-        }
-    }
+// The virtual address the bootloader mapped the complete physical memory at (set by 'init')
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
 
-    /// Returns an iterator over the usable frames specified in the memory map
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        // Get the usable regions from the memory map
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // Get a list of address ranges for each region
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // Change ranges to a list of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // Create a 'PhysFrame' type from each frame start address
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
+// The physical address of the kernel's level 4 table, the one active at boot (set by 'init')
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        // Get the next needed frame
-        let frame = self.usable_frames().nth(self.next);
-        // Incrememnt the next counter
-        self.next += 1;
-        frame
-    }
-}
+/// The kernel's page table mapper, filled in by 'rustos::init_memory'
+///
+/// When both are needed, lock this before 'FRAME_ALLOCATOR' to avoid deadlocks
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// The kernel's physical frame allocator, filled in by 'rustos::init_memory'
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_table_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(level_4_table_frame.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
+    address_space::init(level_4_table);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// The virtual address the complete physical memory is mapped at
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// The frame of the kernel's level 4 table, which every address space shares its kernel entries with
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed)))
+}
+
+/// Returns the virtual address a physical address can be accessed at through the physical memory mapping
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Runs the given closure with the kernel's frame allocator locked
+///
+/// Panics if 'rustos::init_memory' hasn't been called yet
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> R {
+    // Keep interrupt handlers from trying to take the lock while we hold it
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        f(frame_allocator.as_mut().expect("frame allocator not initialized"))
+    })
+}
+
+/// Runs the given closure with the kernel's mapper and frame allocator locked
+///
+/// Panics if 'rustos::init_memory' hasn't been called yet
+pub fn with_mapper_and_allocator<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R
+) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        f(
+            mapper.as_mut().expect("mapper not initialized"),
+            frame_allocator.as_mut().expect("frame allocator not initialized"),
+        )
+    })
+}
+
 // A function to retrieve a mutable reference to the active level 4 page table
 // Unsafe as it requires a caller guarantee that the physical memory is mapped to the given parameter.
 // Also the function can only be called once to avoid aliasing '&mut' references
@@ -0,0 +1,328 @@
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, TranslateResult, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        page_table::PageTableEntry, PageTableIndex, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr
+};
+use super::cow;
+use super::protection::is_writable_executable;
+use super::{kernel_level_4_frame, physical_memory_offset, phys_to_virt, with_frame_allocator};
+
+// The level 4 entries reserved for user mappings, every other entry belongs to the kernel
+// Entry 0 holds the kernel image, so user space starts at the second one. The bootloader's own
+// mappings are pinned to the higher half in Cargo.toml, 'init' checks they stayed out of here
+const USER_LEVEL_4_ENTRIES: core::ops::Range<usize> = 1..128;
+
+/// The first address user pages can be mapped at
+pub const USER_START: u64 = (USER_LEVEL_4_ENTRIES.start as u64) << 39;
+/// The first address after the user range
+pub const USER_END: u64 = (USER_LEVEL_4_ENTRIES.end as u64) << 39;
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// The page isn't in the user range ('USER_START'..'USER_END')
+    NotUserAddress,
+    /// The page isn't mapped
+    NotMapped,
+    /// Mapping the page failed (out of frames or already mapped)
+    Map(MapToError<Size4KiB>),
+    /// The flags ask for writable and executable memory at once
+    WritableExecutable,
+}
+
+/// Checks that nothing the bootloader mapped lies in the user range of the boot level 4 table
+///
+/// Panics otherwise, as switching to an address space would unmap it
+pub(super) fn init(level_4_table: &PageTable) {
+    for index in USER_LEVEL_4_ENTRIES {
+        assert!(level_4_table[index].is_unused(), "level 4 entry {} is mapped at boot but reserved for user space", index);
+    }
+}
+
+/// Whether the given address lies in the part of an address space that's private to it
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    USER_START <= addr.as_u64() && addr.as_u64() < USER_END
+}
+
+/// A set of page tables of its own, e.g. for a user process
+///
+/// The kernel entries of the level 4 table point to the kernel's own tables, so the kernel
+/// stays mapped after switching to it. Only the user range is private, and every table and
+/// frame in there is freed when the address space is dropped
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user range
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let level_4_frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())
+            .ok_or(AddressSpaceError::Map(MapToError::FrameAllocationFailed))?;
+
+        let table = unsafe { table_mut(level_4_frame) };
+        table.zero();
+        sync_kernel_entries(table);
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    /// The frame of the level 4 table, as it's loaded into CR3
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Whether this is the address space the CPU is currently using
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Maps the page to a fresh zeroed frame, accessible from user mode
+    pub fn map(&mut self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, AddressSpaceError> {
+        let frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())
+            .ok_or(AddressSpaceError::Map(MapToError::FrameAllocationFailed))?;
+        unsafe {
+            let frame_ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+            frame_ptr.write_bytes(0, 4096);
+
+            self.map_to(page, frame, flags).map_err(|err| {
+                with_frame_allocator(|frame_allocator| frame_allocator.deallocate_frame(frame));
+                err
+            })?;
+        }
+        Ok(frame)
+    }
+
+    /// Maps the page to the given frame, accessible from user mode
+    ///
+    /// The frame is owned by the address space afterwards and freed with it (see 'cow::share'
+    /// for frames mapped more than once)
+    ///
+    /// Unsafe as the caller must guarantee the frame isn't used by anything else
+    pub unsafe fn map_to(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+        if !is_user_address(page.start_address()) {
+            return Err(AddressSpaceError::NotUserAddress);
+        }
+        if is_writable_executable(flags) {
+            return Err(AddressSpaceError::WritableExecutable);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        // Intermediate tables allow everything, the last level decides
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+        let flush = with_frame_allocator(|frame_allocator| {
+            mapper.map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)
+        }).map_err(AddressSpaceError::Map)?;
+        // Nothing can be cached for an address space that isn't loaded
+        if active { flush.flush() } else { flush.ignore() }
+        Ok(())
+    }
+
+    /// Unmaps the page and frees its frame
+    pub fn unmap(&mut self, page: Page) -> Result<(), AddressSpaceError> {
+        if !is_user_address(page.start_address()) {
+            return Err(AddressSpaceError::NotUserAddress);
+        }
+        let active = self.is_active();
+        let (frame, flush) = self.mapper().unmap(page).map_err(|err| match err {
+            UnmapError::PageNotMapped => AddressSpaceError::NotMapped,
+            _ => panic!("unexpected user page table state: {:?}", err),
+        })?;
+        if active { flush.flush() } else { flush.ignore() }
+        unsafe { cow::free_frame(frame) };
+        Ok(())
+    }
+
+    /// Translates a virtual address of this address space to the physical address it's mapped to
+    pub fn translate_addr(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+
+    /// Returns the frame and flags the page is mapped with
+    pub fn translate_page(&self, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+        match self.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped { frame, flags, .. } => Some((PhysFrame::containing_address(frame.start_address()), flags)),
+            _ => None,
+        }
+    }
+
+    /// Creates a copy of this address space that shares every user frame with it
+    ///
+    /// Writable pages become read-only copy-on-write pages in both, so the first write to one of
+    /// them gives the writer a private copy
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+        let mut child = AddressSpace::new()?;
+        let mut result = Ok(());
+        self.for_each_user_page(|page, entry| {
+            if result.is_err() {
+                return;
+            }
+            let frame = entry.frame().unwrap();
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(cow::COPY_ON_WRITE);
+                entry.set_flags(flags);
+            }
+            cow::share(frame);
+            result = unsafe { child.map_to(page, frame, flags) };
+            if result.is_err() {
+                cow::release(frame);
+            }
+        });
+        // Pages that were writable before might still be cached as such
+        if self.is_active() {
+            x86_64::instructions::tlb::flush_all();
+        }
+        result.map(|()| child)
+    }
+
+    // Calls the closure with every mapped page of the user range and its level 1 entry
+    fn for_each_user_page(&mut self, mut f: impl FnMut(Page, &mut PageTableEntry)) {
+        let level_4 = unsafe { table_mut(self.level_4_frame) };
+        for (i4, level_3) in unsafe { child_tables(level_4) } {
+            if !USER_LEVEL_4_ENTRIES.contains(&usize::from(i4)) {
+                continue;
+            }
+            for (i3, level_2) in unsafe { child_tables(level_3) } {
+                for (i2, level_1) in unsafe { child_tables(level_2) } {
+                    for (i1, entry) in level_1.iter_mut().enumerate().filter(|(_, entry)| !entry.is_unused()) {
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(i4),
+                            PageTableIndex::new(i3),
+                            PageTableIndex::new(i2),
+                            PageTableIndex::new(i1 as u16),
+                        );
+                        f(page, entry);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes this the active address space by loading its level 4 table into CR3
+    ///
+    /// Unsafe as the caller must guarantee nothing still uses the user mappings of the
+    /// current address space
+    pub unsafe fn activate(&self) {
+        // Pick up kernel entries that were created since the last switch
+        sync_kernel_entries(table_mut(self.level_4_frame));
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    // A mapper for this address space's tables
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        let table = unsafe { table_mut(self.level_4_frame) };
+        unsafe { OffsetPageTable::new(table, physical_memory_offset()) }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let table = unsafe { table_mut(self.level_4_frame) };
+        for entry in table.iter_mut().take(USER_LEVEL_4_ENTRIES.end).skip(USER_LEVEL_4_ENTRIES.start) {
+            if let Ok(frame) = entry.frame() {
+                unsafe { free_table(frame, 3) };
+            }
+            entry.set_unused();
+        }
+        with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(self.level_4_frame) });
+    }
+}
+
+/// Switches back to the kernel's own page tables
+///
+/// Unsafe as the caller must guarantee nothing still uses the user mappings of the current address space
+pub unsafe fn activate_kernel() {
+    let (_, flags) = Cr3::read();
+    Cr3::write(kernel_level_4_frame(), flags);
+}
+
+/// Tries to resolve a page fault on a kernel address that's only missing in the active level 4 table
+///
+/// The kernel only ever maps into its own tables, so a level 4 entry it creates while another
+/// address space is active shows up there on the next access
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+    let active = Cr3::read().0;
+    if is_user_address(addr) || active == kernel_level_4_frame() {
+        return false;
+    }
+    let index = usize::from(addr.p4_index());
+    let kernel = unsafe { table_mut(kernel_level_4_frame()) };
+    let table = unsafe { table_mut(active) };
+    if kernel[index].is_unused() || !table[index].is_unused() {
+        return false;
+    }
+    table[index] = kernel[index].clone();
+    true
+}
+
+// Copies every kernel entry of the kernel's level 4 table into the given one
+fn sync_kernel_entries(table: &mut PageTable) {
+    let kernel = unsafe { table_mut(kernel_level_4_frame()) };
+    for index in (0..512).filter(|index| !USER_LEVEL_4_ENTRIES.contains(index)) {
+        let index = PageTableIndex::new(index as u16);
+        table[index] = kernel[index].clone();
+    }
+}
+
+// Frees the table in the given frame together with everything it maps, 'level' is its level in the hierarchy
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    let table = table_mut(frame);
+    for entry in table.iter_mut() {
+        if entry.is_unused() {
+            continue;
+        }
+        // User mappings are only ever made of 4KiB pages
+        let child = entry.frame().expect("huge page in a user address space");
+        if level > 1 {
+            free_table(child, level - 1);
+        } else {
+            cow::free_frame(child);
+        }
+        entry.set_unused();
+    }
+    with_frame_allocator(|frame_allocator| frame_allocator.deallocate_frame(frame));
+}
+
+// Returns the tables the present entries of the given table point to, together with their index
+unsafe fn child_tables(table: &mut PageTable) -> impl Iterator<Item = (u16, &'static mut PageTable)> + '_ {
+    table.iter_mut().enumerate().filter_map(|(index, entry)| {
+        entry.frame().ok().map(|frame| (index as u16, unsafe { table_mut(frame) }))
+    })
+}
+
+// Returns the page table stored in the given frame
+//
+// Unsafe as the caller must guarantee the frame holds a page table and isn't referenced elsewhere
+pub(super) unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+// Test that kernel addresses can't be mapped and user pages are only visible in their own address space
+#[test_case]
+fn test_user_range() {
+    let mut space = AddressSpace::new().unwrap();
+    let kernel_page = Page::containing_address(VirtAddr::new(super::vmalloc::VMALLOC_START));
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    assert!(matches!(space.map(kernel_page, flags), Err(AddressSpaceError::NotUserAddress)));
+
+    // The kernel is mapped in the new space as well
+    let heap = VirtAddr::new(crate::allocator::HEAP_START as u64);
+    assert_eq!(space.translate_addr(heap), super::MAPPER.lock().as_ref().unwrap().translate_addr(heap));
+
+    let page = Page::containing_address(VirtAddr::new(USER_START));
+    assert!(matches!(space.map(page, PageTableFlags::WRITABLE), Err(AddressSpaceError::WritableExecutable)));
+    let frame = space.map(page, flags).unwrap();
+    assert_eq!(space.translate_addr(page.start_address() + 8u64), Some(frame.start_address() + 8u64));
+    assert!(super::MAPPER.lock().as_ref().unwrap().translate_addr(page.start_address()).is_none());
+
+    space.unmap(page).unwrap();
+    assert!(matches!(space.unmap(page), Err(AddressSpaceError::NotMapped)));
+}
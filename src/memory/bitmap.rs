@@ -0,0 +1,269 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use x86_64::{
+    PhysAddr,
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+    VirtAddr
+};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// Frame counters of a 'BitmapFrameAllocator', huge frames count as all the 4KiB frames in them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub in_use: usize,
+    pub peak_in_use: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+}
+
+/// A FrameAllocator that tracks every physical frame with a single bit (set = in use)
+///
+/// The bitmap is built once from the bootloader's memory map and stored in the first usable
+/// region big enough to hold it, so no heap is needed to create it
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    memory_map: &'static MemoryMap, // To tell frames that can be freed from reserved ones
+    bitmap_frames: Range<usize>, // The frames the bitmap lives in, which are never freed
+    total_frames: usize,
+    used_frames: usize,
+    peak_used_frames: usize,
+    allocations: usize,
+    deallocations: usize,
+    // Index of the first word that may still have a free bit (every word before it is full)
+    next_free: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the bitmap from the passed memory map
+    ///
+    /// Unsafe as the caller must guarantee that the passed map is valid and that the complete
+    /// physical memory is mapped at the passed offset. To be valid, all frames marked 'USABLE'
+    /// must truly be usable
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        // The bitmap needs to cover every frame up to the end of the highest usable region
+        let frame_count = usable_regions()
+            .map(|r| (r.range.end_addr() / FRAME_SIZE) as usize)
+            .max()
+            .unwrap_or(0);
+        let word_count = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (word_count * core::mem::size_of::<u64>()) as u64;
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE);
+
+        // Store the bitmap itself at the start of the first usable region that can fit it
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+            .expect("no usable region large enough for the frame bitmap")
+            .range
+            .start_addr();
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, word_count);
+
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            memory_map,
+            bitmap_frames: bitmap_first..bitmap_first + bitmap_frames as usize,
+            total_frames: 0,
+            used_frames: 0,
+            peak_used_frames: 0,
+            allocations: 0,
+            deallocations: 0,
+            next_free: 0,
+        };
+
+        // Start with everything in use and only free the frames the bootloader marked usable
+        allocator.bitmap.fill(u64::MAX);
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.clear_bit(index);
+            }
+            allocator.total_frames += end - start;
+        }
+
+        // Then claim the frames the bitmap lives in
+        for index in allocator.bitmap_frames.clone() {
+            allocator.set_bit(index);
+        }
+        allocator.used_frames = bitmap_frames as usize;
+        allocator.peak_used_frames = allocator.used_frames;
+
+        allocator
+    }
+
+    /// The number of frames the bitmap covers, every frame it hands out has a lower frame number
+    pub fn frame_count(&self) -> usize {
+        self.bitmap.len() * BITS_PER_WORD
+    }
+
+    /// The number of usable frames the allocator manages
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// The number of frames currently handed out (including the ones holding the bitmap)
+    pub fn used_frames(&self) -> usize {
+        self.used_frames
+    }
+
+    /// The number of frames still available for allocation
+    pub fn free_frames(&self) -> usize {
+        self.total_frames - self.used_frames
+    }
+
+    /// Whether the frame is one the allocator hands out, as opposed to reserved memory or the bitmap itself
+    pub fn owns(&self, frame: PhysFrame) -> bool {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        index < self.bitmap.len() * BITS_PER_WORD && self.is_usable(index)
+    }
+
+    /// The allocator's counters since boot
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total_frames,
+            in_use: self.used_frames,
+            peak_in_use: self.peak_used_frames,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+        }
+    }
+
+    // Updates the counters after 'count' frames were handed out
+    fn count_allocation(&mut self, count: usize) {
+        self.used_frames += count;
+        self.peak_used_frames = self.peak_used_frames.max(self.used_frames);
+        self.allocations += count;
+    }
+
+    /// Allocates 'count' physically contiguous frames, starting at a multiple of 'align' frames
+    ///
+    /// Used for huge pages, so it's a plain scan over the aligned candidates
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(count > 0 && align.is_power_of_two());
+        let total_bits = self.bitmap.len() * BITS_PER_WORD;
+        // Nothing before 'next_free' is free, so start at the first aligned candidate after it
+        let first = (self.next_free * BITS_PER_WORD).next_multiple_of(align);
+
+        let start = (first..total_bits.saturating_sub(count - 1))
+            .step_by(align)
+            .find(|&start| self.range_is_free(start, count))?;
+        for index in start..start + count {
+            self.set_bit(index);
+        }
+        self.count_allocation(count);
+        Some(PhysFrame::containing_address(PhysAddr::new(start as u64 * FRAME_SIZE)))
+    }
+
+    /// Frees 'count' contiguous frames previously returned by 'allocate_contiguous'
+    ///
+    /// Unsafe as the caller must guarantee the frames aren't used anymore
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = (start.start_address().as_u64() / FRAME_SIZE) as usize;
+        for index in first..first + count {
+            let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE));
+            FrameDeallocator::<Size4KiB>::deallocate_frame(self, frame);
+        }
+    }
+
+    /// Allocates a frame of the given page size, for huge pages that's a contiguous run of 4KiB frames
+    ///
+    /// Not a 'FrameAllocator' impl, so plain 'allocate_frame' calls don't need their size spelled out
+    pub fn allocate_huge_frame<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let frames = (S::SIZE / FRAME_SIZE) as usize;
+        let start = self.allocate_contiguous(frames, frames)?;
+        Some(PhysFrame::containing_address(start.start_address()))
+    }
+
+    /// Frees a frame returned by 'allocate_huge_frame'
+    ///
+    /// Unsafe as the caller must guarantee the frame isn't used anymore
+    pub unsafe fn deallocate_huge_frame<S: PageSize>(&mut self, frame: PhysFrame<S>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(start, (S::SIZE / FRAME_SIZE) as usize);
+    }
+
+    // Whether all 'count' frames starting at the given index are free
+    fn range_is_free(&self, start: usize, count: usize) -> bool {
+        let mut index = start;
+        while index < start + count {
+            // Check whole words at once where possible
+            if index % BITS_PER_WORD == 0 && index + BITS_PER_WORD <= start + count {
+                if self.bitmap[index / BITS_PER_WORD] != 0 {
+                    return false;
+                }
+                index += BITS_PER_WORD;
+            } else {
+                if self.is_set(index) {
+                    return false;
+                }
+                index += 1;
+            }
+        }
+        true
+    }
+
+    // Whether the frame is one the allocator may hand out, i.e. in a usable region and not part of the bitmap
+    fn is_usable(&self, index: usize) -> bool {
+        let addr = index as u64 * FRAME_SIZE;
+        !self.bitmap_frames.contains(&index)
+            && self.memory_map.iter().any(|r| {
+                r.region_type == MemoryRegionType::Usable && (r.range.start_addr()..r.range.end_addr()).contains(&addr)
+            })
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // Skip over full words, checking 64 frames at a time
+        let word_index = (self.next_free..self.bitmap.len()).find(|&i| self.bitmap[i] != u64::MAX);
+        let word_index = match word_index {
+            Some(word_index) => word_index,
+            None => {
+                self.next_free = self.bitmap.len();
+                return None;
+            }
+        };
+        self.next_free = word_index;
+
+        // The first zero bit in the word is the free frame
+        let bit = (!self.bitmap[word_index]).trailing_zeros() as usize;
+        let index = word_index * BITS_PER_WORD + bit;
+        self.set_bit(index);
+        self.count_allocation(1);
+
+        Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(index < self.bitmap.len() * BITS_PER_WORD, "frame {:?} is outside the bitmap", frame);
+        assert!(self.is_usable(index), "frame {:?} isn't usable memory", frame);
+        assert!(self.is_set(index), "double free of frame {:?}", frame);
+
+        self.clear_bit(index);
+        self.used_frames -= 1;
+        self.deallocations += 1;
+        // Keep the invariant that every word before 'next_free' is full
+        self.next_free = self.next_free.min(index / BITS_PER_WORD);
+    }
+}
@@ -0,0 +1,328 @@
+use alloc::collections::BTreeSet;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    PhysAddr,
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+};
+use crate::allocator::Locked;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// The largest block the allocator hands out is 2^MAX_ORDER frames (4 MiB)
+pub const MAX_ORDER: usize = 10;
+
+// How many frames 'init' takes from the bitmap allocator for the pool, one block of the largest order
+const POOL_FRAMES: usize = 1 << MAX_ORDER;
+
+/// The kernel's pool of physically contiguous frames (DMA buffers and the like), filled by 'init'
+pub static CONTIGUOUS_FRAMES: Locked<BuddyAllocator> = Locked::new(BuddyAllocator::new());
+
+/// Returns the size in bytes of a block of the given order
+pub const fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Returns the smallest order whose blocks can hold the given number of bytes
+pub fn order_for_size(size: u64) -> usize {
+    let frames = size.div_ceil(FRAME_SIZE);
+    frames.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BuddyError {
+    /// The block isn't aligned to the size of its order
+    Misaligned,
+    /// Part of the block is already free
+    DoubleFree,
+}
+
+/// A FrameAllocator for physically contiguous runs of 2^order frames, each aligned to its own size
+///
+/// Free blocks are kept in one set per order and merged with their buddy on free. Only
+/// bookkeeping lives on the heap, the managed frames are never touched, so any range handed
+/// to it must not be owned by another allocator
+///
+/// The kernel's frames all belong to the 'BitmapFrameAllocator', so the global instance only
+/// manages the range 'init' reserves out of it for 'CONTIGUOUS_FRAMES'
+pub struct BuddyAllocator {
+    free_lists: [BTreeSet<u64>; MAX_ORDER + 1], // Start addresses of the free blocks of each order
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BuddyAllocator {
+    /// Creates an allocator without any memory, see 'add_range'
+    pub const fn new() -> Self {
+        BuddyAllocator {
+            free_lists: [const { BTreeSet::new() }; MAX_ORDER + 1],
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// Creates an allocator managing every usable region of the passed memory map
+    ///
+    /// Unsafe as the caller must guarantee that the passed map is valid and that no other
+    /// allocator hands out the same frames
+    pub unsafe fn from_memory_map(memory_map: &MemoryMap) -> Self {
+        let mut allocator = BuddyAllocator::new();
+        for region in memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable) {
+            allocator.add_range(PhysAddr::new(region.range.start_addr()), PhysAddr::new(region.range.end_addr()));
+        }
+        allocator
+    }
+
+    /// Adds the frames between 'start' and 'end' to the allocator, splitting them into the
+    /// largest aligned blocks possible
+    ///
+    /// Unsafe as the caller must guarantee the range is unused and not handed out by anything else
+    pub unsafe fn add_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut addr = start.align_up(FRAME_SIZE).as_u64();
+        let end = end.align_down(FRAME_SIZE).as_u64();
+
+        while addr < end {
+            // The biggest block that starts here, is aligned to its size and still fits
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| addr % block_size(order) == 0 && addr + block_size(order) <= end)
+                .unwrap();
+            self.total_frames += 1 << order;
+            self.free_block(addr, order).expect("range overlaps memory the allocator already manages");
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocates a block of 2^order contiguous frames, aligned to its size
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        assert!(order <= MAX_ORDER, "order {} is bigger than the maximum of {}", order, MAX_ORDER);
+
+        // Find the smallest free block that's big enough
+        let mut current = (order..=MAX_ORDER).find(|&o| !self.free_lists[o].is_empty())?;
+        let addr = *self.free_lists[current].iter().next().unwrap();
+        self.free_lists[current].remove(&addr);
+
+        // Split it in half until it has the requested order, keeping the lower half each time
+        while current > order {
+            current -= 1;
+            self.free_lists[current].insert(addr + block_size(current));
+        }
+
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Frees a block previously returned by 'allocate' with the same order
+    ///
+    /// Fails without changing anything if the block isn't aligned to its order or any part of it
+    /// is already free
+    ///
+    /// Unsafe as the caller must guarantee the block isn't used anymore
+    pub unsafe fn deallocate(&mut self, start: PhysFrame, order: usize) -> Result<(), BuddyError> {
+        let addr = start.start_address().as_u64();
+        if addr % block_size(order) != 0 {
+            return Err(BuddyError::Misaligned);
+        }
+        self.free_block(addr, order)
+    }
+
+    /// The number of frames the allocator manages
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// The number of frames that are currently free
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// The number of free blocks of exactly the given order
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_lists[order].len()
+    }
+
+    /// Puts a block onto the free lists, merging it with its buddy as long as that one is free too
+    fn free_block(&mut self, addr: u64, order: usize) -> Result<(), BuddyError> {
+        if self.overlaps_free_block(addr, order) {
+            return Err(BuddyError::DoubleFree);
+        }
+        self.free_frames += 1 << order;
+
+        let mut addr = addr;
+        let mut order = order;
+        while order < MAX_ORDER {
+            // The buddy is the other half of the block one order up
+            let buddy = addr ^ block_size(order);
+            if !self.free_lists[order].remove(&buddy) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        self.free_lists[order].insert(addr);
+        Ok(())
+    }
+
+    // Whether any part of the block is already free, in a block of its own order, in a bigger one
+    // it was merged into, or in smaller ones it was split into
+    fn overlaps_free_block(&self, addr: u64, order: usize) -> bool {
+        let enclosing = (order..=MAX_ORDER).any(|o| self.free_lists[o].contains(&(addr & !(block_size(o) - 1))));
+        let contained = (0..order).any(|o| self.free_lists[o].range(addr..addr + block_size(order)).next().is_some());
+        enclosing || contained
+    }
+}
+
+/// Reserves the frames of 'CONTIGUOUS_FRAMES' from the kernel's frame allocator
+///
+/// Must be called once, after 'rustos::init_memory' set up the frame allocator
+pub fn init() {
+    let start = super::with_frame_allocator(|allocator| allocator.allocate_contiguous(POOL_FRAMES, POOL_FRAMES))
+        .expect("no physical range left for the contiguous frame pool");
+    let start = start.start_address();
+    interrupts::without_interrupts(|| unsafe {
+        CONTIGUOUS_FRAMES.lock().add_range(start, start + block_size(MAX_ORDER));
+    });
+}
+
+/// Allocates 2^order physically contiguous frames from the kernel's pool, aligned to their size
+pub fn allocate_contiguous(order: usize) -> Option<PhysFrame> {
+    // Keep interrupt handlers from trying to take the lock while we hold it
+    interrupts::without_interrupts(|| CONTIGUOUS_FRAMES.lock().allocate(order))
+}
+
+/// Returns a block from 'allocate_contiguous' with the same order to the kernel's pool
+///
+/// Unsafe as the caller must guarantee the frames aren't used anymore
+pub unsafe fn free_contiguous(start: PhysFrame, order: usize) -> Result<(), BuddyError> {
+    interrupts::without_interrupts(|| CONTIGUOUS_FRAMES.lock().deallocate(start, order))
+}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame, 0).expect("failed to free frame");
+    }
+}
+
+// Builds an allocator over a made up range, its frames are never accessed
+#[cfg(test)]
+fn test_allocator(start: u64, frames: u64) -> BuddyAllocator {
+    let mut allocator = BuddyAllocator::new();
+    unsafe { allocator.add_range(PhysAddr::new(start), PhysAddr::new(start + frames * FRAME_SIZE)) };
+    allocator
+}
+
+// Test that blocks are aligned to their size and don't overlap
+#[test_case]
+fn test_buddy_alignment() {
+    let mut allocator = test_allocator(0x100_0000, 64);
+    let small = allocator.allocate(0).unwrap();
+    let big = allocator.allocate(3).unwrap();
+    assert_eq!(big.start_address().as_u64() % block_size(3), 0);
+    assert!(small.start_address().as_u64() < big.start_address().as_u64()
+        || small.start_address().as_u64() >= big.start_address().as_u64() + block_size(3));
+    assert_eq!(allocator.free_frames(), 64 - 1 - 8);
+}
+
+// Test that a fragmented range can't serve bigger blocks until its frames are freed and merged
+#[test_case]
+fn test_buddy_fragmentation_and_coalescing() {
+    let mut allocator = test_allocator(0x200_0000, 16);
+    assert_eq!(allocator.free_blocks(4), 1);
+
+    let mut frames = [None; 16];
+    for slot in frames.iter_mut() {
+        *slot = allocator.allocate(0);
+    }
+    assert!(allocator.allocate(0).is_none());
+
+    // Free every other frame, leaving 8 free frames none of which are buddies
+    for frame in frames.iter().step_by(2) {
+        unsafe { allocator.deallocate(frame.unwrap(), 0).unwrap() };
+    }
+    assert_eq!(allocator.free_frames(), 8);
+    assert_eq!(allocator.free_blocks(0), 8);
+    assert!(allocator.allocate(1).is_none());
+
+    // Freeing the rest lets everything merge back into a single block
+    for frame in frames.iter().skip(1).step_by(2) {
+        unsafe { allocator.deallocate(frame.unwrap(), 0).unwrap() };
+    }
+    assert_eq!(allocator.free_blocks(0), 0);
+    assert_eq!(allocator.free_blocks(4), 1);
+    assert_eq!(allocator.allocate(4).map(|f| f.start_address().as_u64()), Some(0x200_0000));
+}
+
+// Test that freeing a block that's already part of a merged free block is caught
+#[test_case]
+fn test_buddy_double_free_after_merge() {
+    let mut allocator = test_allocator(0x300_0000, 2);
+    let first = allocator.allocate(0).unwrap();
+    let second = allocator.allocate(0).unwrap();
+    unsafe {
+        allocator.deallocate(first, 0).unwrap();
+        allocator.deallocate(second, 0).unwrap();
+    }
+    // Both merged into the order 1 block, so neither frame is listed on its own anymore
+    assert_eq!(allocator.free_blocks(1), 1);
+    unsafe {
+        assert_eq!(allocator.deallocate(first, 0), Err(BuddyError::DoubleFree));
+        assert_eq!(allocator.deallocate(second, 0), Err(BuddyError::DoubleFree));
+        assert_eq!(allocator.deallocate(first, 1), Err(BuddyError::DoubleFree));
+        assert_eq!(allocator.deallocate(second, 1), Err(BuddyError::Misaligned));
+    }
+    // The rejected frees left the merged block alone
+    assert_eq!(allocator.free_frames(), 2);
+    assert_eq!(allocator.free_blocks(1), 1);
+    assert_eq!(allocator.allocate(1), Some(first));
+}
+
+// Test that the kernel's pool hands out aligned blocks and gets them back
+#[test_case]
+fn test_contiguous_frames_pool() {
+    let free_before = interrupts::without_interrupts(|| CONTIGUOUS_FRAMES.lock().free_frames());
+    let block = allocate_contiguous(4).expect("pool is empty");
+    assert_eq!(block.start_address().as_u64() % block_size(4), 0);
+    assert_eq!(interrupts::without_interrupts(|| CONTIGUOUS_FRAMES.lock().free_frames()), free_before - 16);
+
+    // The frames come out of the bitmap allocator's reserved range, so it mustn't hand them out again
+    let bitmap_frame = super::with_frame_allocator(|allocator| allocator.allocate_frame()).unwrap();
+    assert!(bitmap_frame.start_address() < block.start_address()
+        || bitmap_frame.start_address() >= block.start_address() + block_size(4));
+    unsafe {
+        super::with_frame_allocator(|allocator| allocator.deallocate_frame(bitmap_frame));
+        free_contiguous(block, 4).unwrap();
+        assert_eq!(free_contiguous(block, 4), Err(BuddyError::DoubleFree));
+    }
+    assert_eq!(interrupts::without_interrupts(|| CONTIGUOUS_FRAMES.lock().free_frames()), free_before);
+}
+
+// Test that an unaligned range is split into the largest blocks that fit
+#[test_case]
+fn test_buddy_unaligned_range() {
+    let mut map = MemoryMap::new();
+    map.add_region(bootloader::bootinfo::MemoryRegion {
+        range: bootloader::bootinfo::FrameRange::new(0x3000, 0x10000),
+        region_type: MemoryRegionType::Usable,
+    });
+    let allocator = unsafe { BuddyAllocator::from_memory_map(&map) };
+
+    // 0x3000..0x4000, 0x4000..0x8000 and 0x8000..0x10000
+    assert_eq!(allocator.total_frames(), 13);
+    assert_eq!(allocator.free_blocks(0), 1);
+    assert_eq!(allocator.free_blocks(2), 1);
+    assert_eq!(allocator.free_blocks(3), 1);
+    assert_eq!(order_for_size(5 * FRAME_SIZE), 3);
+}
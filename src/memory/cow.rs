@@ -0,0 +1,138 @@
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{FrameAllocator, FrameDeallocator, page_table::PageTableEntry, Page, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr
+};
+use super::address_space::{is_user_address, table_mut};
+use super::{phys_to_virt, vmalloc, with_frame_allocator};
+
+/// Marks a read-only page that becomes a private writable copy on the first write
+///
+/// One of the bits the CPU ignores and leaves to the OS
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+// How many page table entries point to each frame besides the first, indexed by frame number
+//
+// Allocated once at 'init' for every frame the frame allocator knows, so the page fault handler
+// never has to take a lock or allocate to update it
+static FRAME_REFS: AtomicPtr<AtomicU32> = AtomicPtr::new(core::ptr::null_mut());
+static FRAME_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes writes to read-only pages fault in kernel mode too, which copy-on-write relies on, and
+/// sets up the reference counts
+///
+/// Needs the frame allocator and the kernel mapper
+pub fn init() {
+    let frame_count = with_frame_allocator(|frame_allocator| frame_allocator.frame_count());
+    let size = frame_count * core::mem::size_of::<AtomicU32>();
+    let addr = vmalloc::vmalloc(size, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        .expect("no memory for the copy-on-write reference counts");
+    let refs: *mut AtomicU32 = addr.as_mut_ptr();
+    // The frames come straight from the frame allocator, so they hold whatever was there before
+    unsafe { core::ptr::write_bytes(refs, 0, frame_count) };
+    FRAME_COUNT.store(frame_count, Ordering::Relaxed);
+    FRAME_REFS.store(refs, Ordering::Release);
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+}
+
+// The extra reference count of the frame
+fn frame_refs(frame: PhysFrame) -> &'static AtomicU32 {
+    let refs = FRAME_REFS.load(Ordering::Acquire);
+    assert!(!refs.is_null(), "copy-on-write not initialized");
+    let index = (frame.start_address().as_u64() / 4096) as usize;
+    assert!(index < FRAME_COUNT.load(Ordering::Relaxed), "frame {:?} isn't managed by the frame allocator", frame);
+    unsafe { &*refs.add(index) }
+}
+
+/// Records another page table entry pointing to the frame
+pub fn share(frame: PhysFrame) {
+    frame_refs(frame).fetch_add(1, Ordering::AcqRel);
+}
+
+/// Drops one reference to the frame, returns true if it was the last one and the frame can be freed
+pub fn release(frame: PhysFrame) -> bool {
+    frame_refs(frame)
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |extra| extra.checked_sub(1))
+        .is_err()
+}
+
+/// The number of page table entries pointing to the frame
+pub fn ref_count(frame: PhysFrame) -> usize {
+    frame_refs(frame).load(Ordering::Acquire) as usize + 1
+}
+
+/// Tries to resolve a write to a copy-on-write page of the active address space
+///
+/// The writer gets its own copy of the frame, or the frame itself if nobody else uses it anymore.
+/// Returns false if the fault isn't caused by a copy-on-write page, in which case it's a real error
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let cow_fault = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(cow_fault) || !is_user_address(addr) {
+        return false;
+    }
+    let entry = match active_level_1_entry(addr) {
+        Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return false,
+    };
+
+    let old_frame = entry.frame().unwrap();
+    let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if release(old_frame) {
+        // Every other sharer already made its own copy
+        entry.set_flags(flags);
+    } else {
+        let new_frame = match with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame()) {
+            Some(frame) => frame,
+            None => {
+                share(old_frame);
+                return false;
+            }
+        };
+        unsafe {
+            let src: *const u8 = phys_to_virt(old_frame.start_address()).as_ptr();
+            let dst: *mut u8 = phys_to_virt(new_frame.start_address()).as_mut_ptr();
+            core::ptr::copy_nonoverlapping(src, dst, 4096);
+        }
+        entry.set_frame(new_frame, flags);
+    }
+    x86_64::instructions::tlb::flush(addr);
+    true
+}
+
+/// Gives a frame that was mapped into a user range back, unless other entries still point to it
+///
+/// Unsafe as the caller must guarantee its own mapping of the frame is gone
+pub unsafe fn free_frame(frame: PhysFrame) {
+    if release(frame) {
+        with_frame_allocator(|frame_allocator| frame_allocator.deallocate_frame(frame));
+    }
+}
+
+// Returns the level 1 entry mapping the address in the active address space, if it's mapped with a 4KiB page
+fn active_level_1_entry(addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let mut table = unsafe { table_mut(Cr3::read().0) };
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()].iter() {
+        let frame = table[*index].frame().ok()?;
+        table = unsafe { table_mut(frame) };
+    }
+    let entry = &mut table[page.p1_index()];
+    if entry.is_unused() { None } else { Some(entry) }
+}
+
+// Test that references are counted per sharer and the last release frees the frame
+#[test_case]
+fn test_frame_refs() {
+    let frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame()).unwrap();
+    assert_eq!(ref_count(frame), 1);
+    share(frame);
+    share(frame);
+    assert_eq!(ref_count(frame), 3);
+    assert!(!release(frame));
+    assert!(!release(frame));
+    assert_eq!(ref_count(frame), 1);
+    assert!(release(frame));
+    with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+}
@@ -0,0 +1,211 @@
+use core::arch::x86_64::__cpuid;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr
+};
+use super::bitmap::BitmapFrameAllocator;
+use super::protection::is_writable_executable;
+
+/// Whether the CPU can map 1GiB pages (2MiB pages always exist in long mode)
+pub fn supports_1gib_pages() -> bool {
+    // CPUID leaf 0x8000_0001, EDX bit 26, if the extended leaf exists at all
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+/// Maps 'size' bytes at 'start' to fresh frames, using the largest pages that fit
+///
+/// Parts of the range that aren't aligned for a huge page, or for which no physically contiguous
+/// frames are left, fall back to smaller pages. The frames aren't zeroed, and on an error the
+/// pages mapped so far stay mapped
+pub fn map_fresh(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(start.is_aligned(Size4KiB::SIZE) && size % Size4KiB::SIZE == 0);
+    assert!(!is_writable_executable(flags), "writable and executable mapping requested");
+    let end = start + size;
+    let mut addr = start;
+    let use_1gib_pages = supports_1gib_pages();
+
+    while addr < end {
+        let remaining = end - addr;
+        let mut mapped = 0;
+        if use_1gib_pages {
+            mapped = map_fresh_page::<Size1GiB>(mapper, frame_allocator, addr, remaining, flags)?;
+        }
+        if mapped == 0 {
+            mapped = map_fresh_page::<Size2MiB>(mapper, frame_allocator, addr, remaining, flags)?;
+        }
+        if mapped == 0 {
+            mapped = map_fresh_page::<Size4KiB>(mapper, frame_allocator, addr, remaining, flags)?;
+        }
+        if mapped == 0 {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        addr += mapped;
+    }
+    Ok(())
+}
+
+/// Maps 'size' bytes at 'start' to the physical range at 'phys', using the largest pages that fit
+///
+/// A huge page is only used where both addresses are aligned to it. On an error nothing stays mapped
+///
+/// Unsafe as the caller must guarantee the physical range may be mapped with the given flags
+pub unsafe fn map_physical(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(start.is_aligned(Size4KiB::SIZE) && phys.is_aligned(Size4KiB::SIZE) && size % Size4KiB::SIZE == 0);
+    assert!(!is_writable_executable(flags), "writable and executable mapping requested");
+    let mut offset = 0;
+    let use_1gib_pages = supports_1gib_pages();
+
+    while offset < size {
+        let (addr, frame_addr, remaining) = (start + offset, phys + offset, size - offset);
+        let mapped = if use_1gib_pages && fits::<Size1GiB>(addr, frame_addr, remaining) {
+            map_page::<Size1GiB>(mapper, frame_allocator, addr, frame_addr, flags)
+        } else if fits::<Size2MiB>(addr, frame_addr, remaining) {
+            map_page::<Size2MiB>(mapper, frame_allocator, addr, frame_addr, flags)
+        } else {
+            map_page::<Size4KiB>(mapper, frame_allocator, addr, frame_addr, flags)
+        };
+        match mapped {
+            Ok(size) => offset += size,
+            Err(err) => {
+                unmap_range(mapper, start, offset);
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps every page in the 'size' bytes at 'start', whatever their size, without freeing any frames
+///
+/// Panics if part of the range isn't mapped or a huge page sticks out of it
+pub fn unmap_range(mapper: &mut OffsetPageTable<'static>, start: VirtAddr, size: u64) {
+    let end = start + size;
+    let mut addr = start;
+
+    while addr < end {
+        addr += match mapper.translate(addr) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. } => unmap_page::<Size4KiB>(mapper, addr, end),
+            TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), .. } => unmap_page::<Size2MiB>(mapper, addr, end),
+            TranslateResult::Mapped { frame: MappedFrame::Size1GiB(_), .. } => unmap_page::<Size1GiB>(mapper, addr, end),
+            _ => panic!("unmapping {:?} which isn't mapped", addr),
+        };
+    }
+}
+
+// Whether a page of size 'S' can map 'addr' to 'frame_addr' without going past the end of the range
+fn fits<S: PageSize>(addr: VirtAddr, frame_addr: PhysAddr, remaining: u64) -> bool {
+    addr.is_aligned(S::SIZE) && frame_addr.is_aligned(S::SIZE) && remaining >= S::SIZE
+}
+
+// Maps a page of size 'S' at 'addr' to a fresh frame if the range allows it
+//
+// Returns the number of bytes mapped, which is 0 if the page doesn't fit or there's no frame for it
+fn map_fresh_page<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    addr: VirtAddr,
+    remaining: u64,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    if !addr.is_aligned(S::SIZE) || remaining < S::SIZE {
+        return Ok(0);
+    }
+    let frame = match frame_allocator.allocate_huge_frame::<S>() {
+        Some(frame) => frame,
+        None => return Ok(0),
+    };
+    unsafe {
+        map_page::<S>(mapper, frame_allocator, addr, frame.start_address(), flags).map_err(|err| {
+            frame_allocator.deallocate_huge_frame(frame);
+            err
+        })
+    }
+}
+
+// Maps a single page of size 'S', returning its size
+unsafe fn map_page<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    addr: VirtAddr,
+    frame_addr: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(addr);
+    let frame = PhysFrame::<S>::containing_address(frame_addr);
+    mapper.map_to(page, frame, flags, frame_allocator).map_err(to_4kib_error)?.flush();
+    Ok(S::SIZE)
+}
+
+// Unmaps the page of size 'S' starting at 'addr', returning its size
+fn unmap_page<S: PageSize>(mapper: &mut OffsetPageTable<'static>, addr: VirtAddr, end: VirtAddr) -> u64
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::from_start_address(addr).expect("huge page sticks out of the range");
+    assert!(addr + S::SIZE <= end, "huge page sticks out of the range");
+    let (_, flush) = mapper.unmap(page).unwrap();
+    flush.flush();
+    S::SIZE
+}
+
+// The mapping functions report errors for 4KiB pages, which is what every caller works with
+fn to_4kib_error<S: PageSize>(err: MapToError<S>) -> MapToError<Size4KiB> {
+    match err {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
+
+// Test that a 2MiB page translates every address in it to the matching offset in its frame
+#[test_case]
+fn test_map_2mib_page() {
+    use super::{vmalloc, with_mapper_and_allocator};
+
+    let start = vmalloc::reserve_aligned(512, 512).unwrap();
+    let addr = start.start_address();
+    with_mapper_and_allocator(|mapper, frame_allocator| {
+        let frame = frame_allocator.allocate_huge_frame::<Size2MiB>().expect("no contiguous 2MiB frame left");
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe { map_physical(mapper, frame_allocator, addr, frame.start_address(), Size2MiB::SIZE, flags) }.unwrap();
+
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { frame: MappedFrame::Size2MiB(mapped), .. } => assert_eq!(mapped, frame),
+            _ => panic!("not mapped with a 2MiB page"),
+        }
+        for offset in [0, 0x1000, 0x1234, 0x10_0000, Size2MiB::SIZE - 1].iter() {
+            assert_eq!(mapper.translate_addr(addr + *offset), Some(frame.start_address() + *offset));
+        }
+        assert!(mapper.translate_addr(addr + Size2MiB::SIZE).is_none());
+
+        unmap_range(mapper, addr, Size2MiB::SIZE);
+        assert!(mapper.translate_addr(addr).is_none());
+        unsafe { frame_allocator.deallocate_huge_frame(frame) };
+    });
+    unsafe { vmalloc::release(start, 512) };
+}
@@ -0,0 +1,230 @@
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr
+};
+use super::{cow, phys_to_virt};
+
+/// Where the dumps are printed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Vga,
+    Serial,
+}
+
+/// A run of virtually and physically contiguous memory mapped with the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub phys_start: PhysAddr,
+    /// The effective flags, so a page only counts as writable or user accessible if every level
+    /// allows it, and as non-executable if any level forbids it
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    /// The size of the range in bytes
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Whether the range covers no bytes at all
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Whether the mapping at 'start' continues this range
+    fn continues_with(&self, start: VirtAddr, phys_start: PhysAddr, flags: PageTableFlags) -> bool {
+        self.end == start && self.phys_start + self.len() == phys_start && self.flags == flags
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag: PageTableFlags, set: char| if self.flags.contains(flag) { set } else { '-' };
+        write!(
+            f,
+            "{:#014x}-{:#014x} -> {:#012x} {:>6} r{}{} {}{}{}{}{}",
+            self.start.as_u64(),
+            self.end.as_u64(),
+            self.phys_start.as_u64(),
+            Size(self.len()),
+            flag(PageTableFlags::WRITABLE, 'w'),
+            if self.flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+            flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+            flag(PageTableFlags::GLOBAL, 'g'),
+            flag(PageTableFlags::WRITE_THROUGH, 't'),
+            flag(PageTableFlags::NO_CACHE, 'c'),
+            flag(cow::COPY_ON_WRITE, 'o'),
+        )
+    }
+}
+
+// Formats a byte count with the biggest unit it's a whole multiple of
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [(u64, &str); 3] = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+        let text = UNITS.iter()
+            .find(|(unit, _)| self.0 >= *unit && self.0 % unit == 0)
+            .map(|(unit, name)| alloc::format!("{}{}", self.0 / unit, name))
+            .unwrap_or_else(|| alloc::format!("{}", self.0));
+        f.pad(&text)
+    }
+}
+
+// The flags that decide whether two neighbouring mappings are shown as one range
+fn relevant_flags(flags: PageTableFlags) -> PageTableFlags {
+    flags - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY | PageTableFlags::HUGE_PAGE | PageTableFlags::PRESENT)
+}
+
+/// Walks the active page tables from CR3 and calls the closure for each mapped range, in address order
+pub fn for_each_mapped_range(mut f: impl FnMut(MappedRange)) {
+    let level_4 = unsafe { &*phys_to_virt(Cr3::read().0.start_address()).as_ptr::<PageTable>() };
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    let mut current: Option<MappedRange> = None;
+    walk_table(level_4, 4, 0, inherited, &mut |start, phys_start, size, flags| {
+        match current.as_mut() {
+            Some(range) if range.continues_with(start, phys_start, flags) => range.end += size,
+            _ => {
+                if let Some(range) = current.take() {
+                    f(range);
+                }
+                current = Some(MappedRange { start, end: start + size, phys_start, flags });
+            }
+        }
+    });
+    if let Some(range) = current {
+        f(range);
+    }
+}
+
+// Calls the closure for every leaf mapping below the table, 'inherited' holds the permissions of the levels above
+fn walk_table(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    inherited: PageTableFlags,
+    f: &mut impl FnMut(VirtAddr, PhysAddr, u64, PageTableFlags),
+) {
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+    for (index, entry) in table.iter().enumerate().filter(|(_, entry)| !entry.is_unused()) {
+        let addr = VirtAddr::new_truncate(base + index as u64 * entry_size);
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let permissions = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let effective = (flags - permissions) | (flags & inherited & permissions) | (inherited & PageTableFlags::NO_EXECUTE);
+
+        if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            let child = unsafe { &*phys_to_virt(entry.addr()).as_ptr::<PageTable>() };
+            walk_table(child, level - 1, addr.as_u64(), effective, f);
+        } else {
+            f(addr, entry.addr(), entry_size, relevant_flags(effective));
+        }
+    }
+}
+
+/// Prints the compressed view of the active page tables
+pub fn dump_page_tables<W: Write + ?Sized>(out: &mut W) -> fmt::Result {
+    writeln!(out, "virtual range                     physical        size flags")?;
+    let mut result = Ok(());
+    for_each_mapped_range(|range| {
+        if result.is_ok() {
+            result = writeln!(out, "{}", range);
+        }
+    });
+    result
+}
+
+/// Prints every region of the bootloader's memory map, followed by the total size of each type
+pub fn dump_memory_map<W: Write + ?Sized>(memory_map: &MemoryMap, out: &mut W) -> fmt::Result {
+    let mut totals: Vec<(MemoryRegionType, u64)> = Vec::new();
+    for region in memory_map.iter() {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        writeln!(out, "{:#012x}-{:#012x} {:>6} {:?}", start, end, Size(end - start), region.region_type)?;
+        match totals.iter_mut().find(|(region_type, _)| *region_type == region.region_type) {
+            Some((_, total)) => *total += end - start,
+            None => totals.push((region.region_type, end - start)),
+        }
+    }
+    for (region_type, total) in totals {
+        // Derived Debug output ignores padding, so format it first
+        let name = alloc::format!("{:?}", region_type);
+        writeln!(out, "{:>16} {:>6}", name, Size(total))?;
+    }
+    Ok(())
+}
+
+/// Prints the page table dump to the VGA buffer or the serial port
+pub fn print_page_tables(output: Output) {
+    print_with(output, |out| dump_page_tables(out));
+}
+
+/// Prints the memory map dump to the VGA buffer or the serial port
+pub fn print_memory_map(memory_map: &MemoryMap, output: Output) {
+    print_with(output, |out| dump_memory_map(memory_map, out));
+}
+
+// Runs the closure with the chosen output locked
+fn print_with(output: Output, f: impl FnOnce(&mut dyn Write) -> fmt::Result) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let result = match output {
+            Output::Vga => f(&mut *crate::vga_buffer::WRITER.lock()),
+            Output::Serial => f(&mut *crate::serial::SERIAL1.lock()),
+        };
+        result.expect("printing the dump failed");
+    });
+}
+
+// Test that the heap shows up as one writable, non-executable range
+#[test_case]
+fn test_mapped_ranges() {
+    let heap_start = VirtAddr::new(crate::allocator::HEAP_START as u64);
+    let mut heap = None;
+    let mut last_end = VirtAddr::new(0);
+    for_each_mapped_range(|range| {
+        assert!(range.start >= last_end);
+        last_end = range.end;
+        if range.start <= heap_start && heap_start < range.end {
+            heap = Some(range);
+        }
+    });
+
+    let heap = heap.expect("heap isn't mapped");
+    assert!(heap.flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    assert!(!heap.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+
+    let mut line = alloc::string::String::new();
+    write!(line, "{}", heap).unwrap();
+    assert!(line.contains("rw-"));
+}
+
+// Test that the memory map dump lists each region and sums up each type
+#[test_case]
+fn test_dump_memory_map() {
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    let mut map = MemoryMap::new();
+    for (start, end, region_type) in [
+        (0x0, 0x1000, MemoryRegionType::FrameZero),
+        (0x1000, 0x9_f000, MemoryRegionType::Usable),
+        (0x10_0000, 0x30_0000, MemoryRegionType::Usable),
+    ].iter() {
+        map.add_region(MemoryRegion { range: FrameRange::new(*start, *end), region_type: *region_type });
+    }
+
+    let mut dump = alloc::string::String::new();
+    dump_memory_map(&map, &mut dump).unwrap();
+    assert_eq!(dump.lines().count(), 5);
+    assert!(dump.contains("0x0000100000-0x0000300000     2M Usable"));
+    // 632K + 2M of usable memory in total
+    assert!(dump.contains("          Usable  2680K"));
+}
@@ -0,0 +1,304 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    PhysAddr,
+    registers::model_specific::Msr,
+    structures::paging::{Page, PageSize, PageTableFlags, Size2MiB, Size4KiB},
+    VirtAddr
+};
+use super::vmalloc::{self, VmallocError};
+use super::{huge, with_mapper_and_allocator};
+
+// The page attribute table MSR, which decides what the PWT/PCD(/PAT) bits of an entry mean
+const IA32_PAT: u32 = 0x277;
+
+// Memory type encodings used in the PAT
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+
+// Our PAT layout, only PA2 differs from the power-on default (UC- becomes WC)
+// The upper four entries mirror the lower ones so the PAT bit itself never matters
+const PAT_LAYOUT: u64 = {
+    let low = PAT_WB | PAT_WT << 8 | PAT_WC << 16 | PAT_UC << 24;
+    low | low << 32
+};
+
+static PAT_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// How the CPU caches accesses to a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal cached memory
+    WriteBack,
+    /// Reads are cached, writes go straight to memory
+    WriteThrough,
+    /// Writes are buffered and combined, reads aren't cached (framebuffers)
+    ///
+    /// Falls back to uncached if the CPU has no PAT
+    WriteCombining,
+    /// Nothing is cached or reordered (device registers)
+    Uncached,
+}
+
+impl CacheMode {
+    /// The page table flags selecting this mode with our PAT layout
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PageTableFlags::NO_CACHE,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Programs the PAT with our layout so every 'CacheMode' can be selected
+///
+/// Needs to run before any write combining mapping is created
+pub fn init_pat() {
+    // CPUID leaf 1, EDX bit 16 tells us whether there's a PAT
+    let supported = unsafe { core::arch::x86_64::__cpuid(1) }.edx & (1 << 16) != 0;
+    PAT_SUPPORTED.store(supported, Ordering::Relaxed);
+    if !supported {
+        return;
+    }
+
+    // Only the unused PA2 entry changes, so no cached data can be affected
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Msr::new(IA32_PAT).write(PAT_LAYOUT);
+        x86_64::instructions::tlb::flush_all();
+    });
+}
+
+/// Whether 'init_pat' found and programmed a PAT
+pub fn pat_supported() -> bool {
+    PAT_SUPPORTED.load(Ordering::Relaxed)
+}
+
+/// A mapping of physical device memory, accessed with volatile reads and writes at byte offsets
+///
+/// Only the bounds are checked, the type read or written is up to the caller. Drivers with a
+/// fixed register layout should use 'Mmio' instead. The mapping is removed when the region is dropped
+#[derive(Debug)]
+pub struct MmioRegion {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    len: usize,
+    mapped_start: VirtAddr, // The page aligned part that's actually mapped
+    mapped_size: u64,
+    reserved_start: Page, // The part of the vmalloc range reserved for it, which may be bigger
+    reserved_pages: u64,
+}
+
+impl MmioRegion {
+    /// The physical address the region starts at
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// The virtual address the region is mapped at
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// The size of the region in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the region has no bytes, never true for one returned by 'map_mmio'
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a raw pointer to a 'T' at the given byte offset
+    ///
+    /// Panics if the 'T' wouldn't be fully inside the region
+    pub fn as_ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.len,
+            "MMIO access at {:#x} is outside the region of {:#x} bytes", offset, self.len
+        );
+        (self.virt + offset).as_mut_ptr()
+    }
+
+    /// Reads a 'T' at the given byte offset
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.as_ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Writes a 'T' at the given byte offset
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.as_ptr::<T>(offset).write_volatile(value) }
+    }
+}
+
+/// A single device register, only ever accessed with volatile reads and writes
+///
+/// Meant as the field type of '#[repr(C)]' register blocks mapped with 'Mmio'
+#[repr(transparent)]
+pub struct Register<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+impl<T: Copy> Register<T> {
+    /// Reads the register's current value
+    pub fn read(&self) -> T {
+        unsafe { self.value.get().read_volatile() }
+    }
+
+    /// Writes the given value to the register
+    pub fn write(&self, value: T) {
+        unsafe { self.value.get().write_volatile(value) }
+    }
+}
+
+/// A typed mapping of a device's register block 'T', which derefs to the block
+///
+/// The mapping is removed when it's dropped
+pub struct Mmio<T> {
+    region: MmioRegion,
+    block: PhantomData<T>,
+}
+
+impl<T> Mmio<T> {
+    /// Maps the register block at 'phys' with the given cache mode
+    ///
+    /// Panics if 'phys' isn't aligned for 'T'
+    ///
+    /// Unsafe as the caller must guarantee the same as for 'map_mmio', and that the device's
+    /// registers at 'phys' really have the layout of 'T'
+    pub unsafe fn map(phys: PhysAddr, cache_mode: CacheMode) -> Result<Self, VmallocError> {
+        assert!(phys.is_aligned(core::mem::align_of::<T>() as u64), "register block at {:?} is misaligned", phys);
+        Ok(Mmio {
+            region: map_mmio(phys, core::mem::size_of::<T>(), cache_mode)?,
+            block: PhantomData,
+        })
+    }
+
+    /// The untyped region the block is mapped in
+    pub fn region(&self) -> &MmioRegion {
+        &self.region
+    }
+}
+
+impl<T> Deref for Mmio<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // The mapping lives as long as 'self' and is aligned as the physical address is
+        unsafe { &*self.region.as_ptr::<T>(0) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        // The frames belong to the device, so they're just unmapped and not freed
+        with_mapper_and_allocator(|mapper, _| huge::unmap_range(mapper, self.mapped_start, self.mapped_size));
+        unsafe { vmalloc::release(self.reserved_start, self.reserved_pages) };
+    }
+}
+
+/// Maps 'len' bytes of physical device memory starting at 'phys' with the given cache mode
+///
+/// Unsafe as the caller must guarantee the range is device memory (or otherwise not used as
+/// normal memory), since mapping RAM with a different cache mode than its other mappings is undefined
+pub unsafe fn map_mmio(phys: PhysAddr, len: usize, cache_mode: CacheMode) -> Result<MmioRegion, VmallocError> {
+    assert!(len > 0);
+    let phys_start = phys.align_down(Size4KiB::SIZE);
+    let mapped_size = (phys + len).align_up(Size4KiB::SIZE) - phys_start;
+    let pages = mapped_size / Size4KiB::SIZE;
+
+    // Big regions get a virtual start that lines up with the physical one, so they can use 2MiB pages
+    let pages_per_huge_page = Size2MiB::SIZE / Size4KiB::SIZE;
+    let (reserved_start, reserved_pages, mapped_start) = if mapped_size >= Size2MiB::SIZE {
+        let reserved_pages = pages + pages_per_huge_page - 1;
+        let reserved_start = vmalloc::reserve_aligned(reserved_pages, pages_per_huge_page)
+            .ok_or(VmallocError::OutOfVirtualSpace)?;
+        let mapped_start = reserved_start.start_address() + phys_start.as_u64() % Size2MiB::SIZE;
+        (reserved_start, reserved_pages, mapped_start)
+    } else {
+        let reserved_start = vmalloc::reserve(pages).ok_or(VmallocError::OutOfVirtualSpace)?;
+        (reserved_start, pages, reserved_start.start_address())
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache_mode.flags();
+    let result = with_mapper_and_allocator(|mapper, frame_allocator| {
+        huge::map_physical(mapper, frame_allocator, mapped_start, phys_start, mapped_size, flags)
+    });
+    if let Err(err) = result {
+        vmalloc::release(reserved_start, reserved_pages);
+        return Err(VmallocError::Map(err));
+    }
+
+    Ok(MmioRegion {
+        phys,
+        virt: mapped_start + (phys - phys_start),
+        len,
+        mapped_start,
+        mapped_size,
+        reserved_start,
+        reserved_pages,
+    })
+}
+
+// Test that the mapping has the requested cache flags and reaches the right physical memory
+#[test_case]
+fn test_map_mmio() {
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Translate};
+    use x86_64::structures::paging::mapper::TranslateResult;
+    use super::{phys_to_virt, with_frame_allocator, MAPPER};
+
+    let translate = |virt: VirtAddr| match MAPPER.lock().as_ref().unwrap().translate(virt) {
+        TranslateResult::Mapped { frame, flags, .. } => Some((frame.start_address(), flags)),
+        _ => None,
+    };
+
+    // The local APIC's page is real device memory, its version register is never 0
+    let apic_base = PhysAddr::new(unsafe { Msr::new(0x1B).read() } & 0x000f_ffff_ffff_f000);
+    let region = unsafe { map_mmio(apic_base + 0x30u64, 4, CacheMode::Uncached) }.unwrap();
+    assert_eq!(region.virt_addr().as_u64() % 4096, 0x30);
+    let (mapped, flags) = translate(region.virt_addr()).expect("MMIO region isn't mapped");
+    assert_eq!(mapped, apic_base);
+    assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+    assert_ne!(region.read::<u32>(0), 0);
+    drop(region);
+
+    // A spare RAM frame as stand-in for writable device memory, mapped write-back like its
+    // mapping through the physical memory offset, as RAM must never get two memory types
+    let frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame()).unwrap();
+    let phys = frame.start_address() + 0x10u64;
+    let region = unsafe { map_mmio(phys, 64, CacheMode::WriteBack) }.unwrap();
+    assert_eq!(translate(region.virt_addr()).map(|(mapped, _)| mapped), Some(frame.start_address()));
+
+    region.write::<u32>(4, 0x1234_5678);
+    let through_offset: *const u32 = phys_to_virt(phys + 4u64).as_ptr();
+    assert_eq!(unsafe { through_offset.read_volatile() }, 0x1234_5678);
+    assert_eq!(region.read::<u32>(4), 0x1234_5678);
+
+    let virt = region.virt_addr();
+    drop(region);
+    assert!(translate(virt).is_none());
+
+    // The same through a typed register block, all of it at fixed offsets
+    #[repr(C)]
+    struct Block {
+        control: Register<u32>,
+        _reserved: u32,
+        data: Register<u64>,
+    }
+    let block = unsafe { Mmio::<Block>::map(phys, CacheMode::WriteBack) }.unwrap();
+    block.data.write(0xdead_beef_0000_0001);
+    block.control.write(7);
+    assert_eq!(unsafe { phys_to_virt(phys + 8u64).as_ptr::<u64>().read_volatile() }, 0xdead_beef_0000_0001);
+    assert_eq!(block.control.read(), 7);
+    assert_eq!(block.region().len(), core::mem::size_of::<Block>());
+    let virt = block.region().virt_addr();
+    drop(block);
+    assert!(translate(virt).is_none());
+    with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+}
@@ -0,0 +1,128 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr
+};
+use super::{phys_to_virt, MAPPER};
+
+// ELF constants needed to find the kernel's code
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+/// Lets page table entries use the NO_EXECUTE bit, without this it's a reserved bit and faults
+///
+/// Needs to run before anything is mapped with 'NO_EXECUTE'
+pub fn enable_nxe() {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+}
+
+/// Whether a mapping with these flags could be both written and executed
+///
+/// The mapping helpers refuse such requests, writable memory has to ask for 'NO_EXECUTE' explicitly
+pub fn is_writable_executable(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+/// How many pages the W^X pass changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtectionStats {
+    pub code_pages: usize, // Mapped read-only and executable
+    pub data_pages: usize, // Mapped non-executable (huge pages count once)
+}
+
+/// Walks the kernel's page tables and makes the kernel's code read-only and everything else
+/// non-executable, whatever flags the bootloader picked
+///
+/// The code is found through the program headers of the kernel ELF the bootloader left in memory
+pub fn enforce_wx(memory_map: &MemoryMap) -> ProtectionStats {
+    let code = kernel_code_segments(memory_map);
+    let mut stats = ProtectionStats::default();
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let level_4 = mapper.as_mut().expect("mapper not initialized").level_4_table();
+        protect_table(level_4, 4, 0, &code, &mut stats);
+    });
+    x86_64::instructions::tlb::flush_all();
+    stats
+}
+
+// Applies W^X to every mapping below the table, 'base' is the first address the table covers
+fn protect_table(table: &mut PageTable, level: u8, base: u64, code: &[Range<u64>], stats: &mut ProtectionStats) {
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+    for (index, entry) in table.iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        // Level 4 addresses in the upper half need to be sign extended
+        let addr = VirtAddr::new_truncate(base + index as u64 * entry_size).as_u64();
+        let mut flags = entry.flags();
+
+        if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            let child = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() };
+            protect_table(child, level - 1, addr, code, stats);
+            continue;
+        }
+
+        let is_code = code.iter().any(|range| range.start < addr + entry_size && addr < range.end);
+        if is_code && level == 1 {
+            flags.remove(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE);
+            stats.code_pages += 1;
+        } else if !is_code {
+            flags.insert(PageTableFlags::NO_EXECUTE);
+            stats.data_pages += 1;
+        }
+        // A huge page with code in it can't be split here, so it's left alone
+        entry.set_flags(flags);
+    }
+}
+
+// Returns the virtual ranges of the kernel's executable segments
+fn kernel_code_segments(memory_map: &MemoryMap) -> Vec<Range<u64>> {
+    // The bootloader loads the kernel ELF file as is and maps its segments from there
+    let elf = memory_map.iter()
+        .filter(|r| r.region_type == MemoryRegionType::Kernel)
+        .map(|r| phys_to_virt(PhysAddr::new(r.range.start_addr())))
+        .find(|&addr| unsafe { addr.as_ptr::<[u8; 4]>().read() } == ELF_MAGIC)
+        .expect("kernel ELF not found in the memory map");
+
+    unsafe {
+        let read_u16 = |offset: u64| (elf + offset).as_ptr::<u16>().read_unaligned();
+        let read_u32 = |offset: u64| (elf + offset).as_ptr::<u32>().read_unaligned();
+        let read_u64 = |offset: u64| (elf + offset).as_ptr::<u64>().read_unaligned();
+
+        // e_phoff, e_phentsize and e_phnum of the ELF64 header
+        let program_headers = read_u64(0x20);
+        let header_size = read_u16(0x36) as u64;
+        let header_count = read_u16(0x38) as u64;
+
+        (0..header_count)
+            .map(|i| program_headers + i * header_size)
+            // p_type and p_flags
+            .filter(|&header| read_u32(header) == PT_LOAD && read_u32(header + 4) & PF_X != 0)
+            // p_vaddr and p_memsz
+            .map(|header| read_u64(header + 0x10)..read_u64(header + 0x10) + read_u64(header + 0x28))
+            .collect()
+    }
+}
+
+// Test that the running code is read-only and the heap isn't executable
+#[test_case]
+fn test_wx_flags() {
+    use x86_64::structures::paging::{mapper::TranslateResult, Translate};
+
+    let flags_of = |addr: u64| match MAPPER.lock().as_ref().unwrap().translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:#x} isn't mapped", addr),
+    };
+
+    let code = flags_of(enable_nxe as fn() as usize as u64);
+    assert!(!code.contains(PageTableFlags::WRITABLE) && !code.contains(PageTableFlags::NO_EXECUTE));
+    let heap = flags_of(crate::allocator::HEAP_START as u64);
+    assert!(heap.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    assert!(is_writable_executable(PageTableFlags::WRITABLE));
+}
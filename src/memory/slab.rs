@@ -0,0 +1,351 @@
+use core::{fmt, marker::PhantomData, mem, ptr::{self, NonNull}};
+use spin::Mutex;
+use x86_64::{
+    PhysAddr,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+};
+use super::{phys_to_virt, physical_memory_offset, with_frame_allocator};
+
+const SLAB_SIZE: usize = 4096;
+
+// The bookkeeping at the start of every slab (a single physical frame)
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeObject, // The first free object in this slab
+    in_use: usize,
+}
+
+// A free object, linked into its slab's free list through the object's own memory
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// A doubly linked list of slabs
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList { head: ptr::null_mut(), len: 0 }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut Slab> {
+        let slab = self.head;
+        if slab.is_null() {
+            None
+        } else {
+            self.remove(slab);
+            Some(slab)
+        }
+    }
+}
+
+struct SlabCacheInner {
+    full: SlabList,
+    partial: SlabList,
+    empty: SlabList,
+    active_objects: usize,
+    allocations: u64,
+    frees: u64,
+}
+
+// The slabs are only ever touched with the cache's lock held
+unsafe impl Send for SlabCacheInner {}
+
+/// A cache of fixed-size objects of type 'T', carved out of whole physical frames
+///
+/// Each slab is one frame accessed through the physical memory mapping, so the cache never
+/// touches the kernel heap. Freed objects are kept around for reuse until 'shrink' is called
+pub struct SlabCache<T> {
+    name: &'static str,
+    inner: Mutex<SlabCacheInner>,
+    _marker: PhantomData<T>,
+}
+
+// Objects are handed between threads through raw pointers, so 'T' only needs to be Send
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    // Objects need room for a free list link when they're not in use
+    const OBJECT_ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeObject>());
+    const OBJECT_SIZE: usize =
+        align_up(max(mem::size_of::<T>(), mem::size_of::<FreeObject>()), Self::OBJECT_ALIGN);
+    // The first object starts after the slab's header
+    const FIRST_OBJECT: usize = align_up(mem::size_of::<Slab>(), Self::OBJECT_ALIGN);
+    const OBJECTS_PER_SLAB: usize = (SLAB_SIZE - Self::FIRST_OBJECT) / Self::OBJECT_SIZE;
+
+    /// Creates an empty cache, no memory is taken until the first allocation
+    pub const fn new(name: &'static str) -> Self {
+        SlabCache {
+            name,
+            inner: Mutex::new(SlabCacheInner {
+                full: SlabList::new(),
+                partial: SlabList::new(),
+                empty: SlabList::new(),
+                active_objects: 0,
+                allocations: 0,
+                frees: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Moves the given value into a free object of the cache
+    ///
+    /// Returns None if a new slab was needed but there are no free frames left
+    pub fn alloc(&self, value: T) -> Option<NonNull<T>> {
+        assert!(Self::OBJECTS_PER_SLAB > 0, "type is too big for slab cache '{}'", self.name);
+
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            let slab = unsafe {
+                match inner.partial.pop() {
+                    Some(slab) => slab,
+                    None => match inner.empty.pop() {
+                        Some(slab) => slab,
+                        None => Self::grow()?,
+                    },
+                }
+            };
+
+            unsafe {
+                // Take the first free object of the slab and file the slab under its new state
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                (*slab).in_use += 1;
+                if (*slab).in_use == Self::OBJECTS_PER_SLAB {
+                    inner.full.push(slab);
+                } else {
+                    inner.partial.push(slab);
+                }
+                inner.active_objects += 1;
+                inner.allocations += 1;
+
+                let object = object as *mut T;
+                object.write(value);
+                Some(NonNull::new_unchecked(object))
+            }
+        })
+    }
+
+    /// Drops the object in place and returns its memory to the cache
+    ///
+    /// Unsafe as the caller must guarantee the pointer came from 'alloc' on this cache and
+    /// isn't used afterwards
+    pub unsafe fn free(&self, object: NonNull<T>) {
+        ptr::drop_in_place(object.as_ptr());
+
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            // Slabs are frame aligned, so the header is at the start of the object's frame
+            let slab = (object.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+            let was_full = (*slab).in_use == Self::OBJECTS_PER_SLAB;
+
+            let free_object = object.as_ptr() as *mut FreeObject;
+            (*free_object).next = (*slab).free;
+            (*slab).free = free_object;
+            (*slab).in_use -= 1;
+
+            if was_full {
+                inner.full.remove(slab);
+            } else {
+                inner.partial.remove(slab);
+            }
+            if (*slab).in_use == 0 {
+                inner.empty.push(slab);
+            } else {
+                inner.partial.push(slab);
+            }
+            inner.active_objects -= 1;
+            inner.frees += 1;
+        })
+    }
+
+    /// Gives the frames of all empty slabs back to the frame allocator
+    ///
+    /// Returns the number of slabs released
+    pub fn shrink(&self) -> usize {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            let mut released = 0;
+            while let Some(slab) = unsafe { inner.empty.pop() } {
+                let frame = PhysFrame::containing_address(PhysAddr::new(
+                    slab as u64 - physical_memory_offset().as_u64(),
+                ));
+                with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+                released += 1;
+            }
+            released
+        })
+    }
+
+    /// Returns the current statistics of this cache
+    pub fn stats(&self) -> SlabStats {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let inner = self.inner.lock();
+            let slabs = inner.full.len + inner.partial.len + inner.empty.len;
+            SlabStats {
+                name: self.name,
+                object_size: Self::OBJECT_SIZE,
+                objects_per_slab: Self::OBJECTS_PER_SLAB,
+                full_slabs: inner.full.len,
+                partial_slabs: inner.partial.len,
+                empty_slabs: inner.empty.len,
+                active_objects: inner.active_objects,
+                total_objects: slabs * Self::OBJECTS_PER_SLAB,
+                allocations: inner.allocations,
+                frees: inner.frees,
+            }
+        })
+    }
+
+    /// Allocates a frame for a new slab and threads all of its objects onto the free list
+    unsafe fn grow() -> Option<*mut Slab> {
+        let frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())?;
+        let slab_addr = phys_to_virt(frame.start_address()).as_u64() as usize;
+
+        // Link the objects back to front so they get handed out in address order
+        let mut free: *mut FreeObject = ptr::null_mut();
+        for i in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let object = (slab_addr + Self::FIRST_OBJECT + i * Self::OBJECT_SIZE) as *mut FreeObject;
+            object.write(FreeObject { next: free });
+            free = object;
+        }
+
+        let slab = slab_addr as *mut Slab;
+        slab.write(Slab {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free,
+            in_use: 0,
+        });
+        Some(slab)
+    }
+}
+
+impl<T> Drop for SlabCache<T> {
+    fn drop(&mut self) {
+        assert_eq!(self.stats().active_objects, 0, "slab cache '{}' dropped with live objects", self.name);
+        self.shrink();
+    }
+}
+
+/// A snapshot of a slab cache's usage, printable with 'println!' or 'serial_println!'
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub full_slabs: usize,
+    pub partial_slabs: usize,
+    pub empty_slabs: usize,
+    pub active_objects: usize,
+    pub total_objects: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "slab '{}': {}/{} objects of {} bytes in use, slabs {} full {} partial {} empty, {} allocs {} frees",
+            self.name,
+            self.active_objects,
+            self.total_objects,
+            self.object_size,
+            self.full_slabs,
+            self.partial_slabs,
+            self.empty_slabs,
+            self.allocations,
+            self.frees,
+        )
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// Test that a freed object is handed out again and the counters follow along
+#[test_case]
+fn test_slab_alloc_free_reuse() {
+    let cache: SlabCache<[u64; 4]> = SlabCache::new("test_reuse");
+    let first = cache.alloc([1, 2, 3, 4]).unwrap();
+    assert_eq!(unsafe { *first.as_ptr() }, [1, 2, 3, 4]);
+    let stats = cache.stats();
+    assert_eq!(stats.active_objects, 1);
+    assert_eq!(stats.partial_slabs, 1);
+
+    unsafe { cache.free(first) };
+    let stats = cache.stats();
+    assert_eq!(stats.active_objects, 0);
+    assert_eq!(stats.empty_slabs, 1);
+
+    let second = cache.alloc([5, 6, 7, 8]).unwrap();
+    assert_eq!(second, first);
+    unsafe { cache.free(second) };
+}
+
+// Test filling several slabs, freeing everything and releasing the frames again
+#[test_case]
+fn test_slab_grow_and_shrink() {
+    let cache: SlabCache<u64> = SlabCache::new("test_shrink");
+    let free_frames_before = with_frame_allocator(|frame_allocator| frame_allocator.free_frames());
+    let count = SlabCache::<u64>::OBJECTS_PER_SLAB * 3;
+
+    let mut objects = alloc::vec::Vec::new();
+    for i in 0..count {
+        objects.push(cache.alloc(i as u64).unwrap());
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.full_slabs, 3);
+    assert_eq!(stats.active_objects, count);
+    let text = alloc::format!("{}", stats);
+    assert!(text.starts_with("slab 'test_shrink': "), "unexpected stats: {}", text);
+    assert!(text.contains(&alloc::format!("{}/{} objects of 8 bytes in use", count, count)), "unexpected stats: {}", text);
+    assert!(text.contains("slabs 3 full 0 partial 0 empty"), "unexpected stats: {}", text);
+
+    for (i, object) in objects.iter().enumerate() {
+        assert_eq!(unsafe { *object.as_ptr() }, i as u64);
+    }
+    for object in objects {
+        unsafe { cache.free(object) };
+    }
+    assert_eq!(cache.stats().empty_slabs, 3);
+
+    assert_eq!(cache.shrink(), 3);
+    assert_eq!(cache.stats().total_objects, 0);
+    let free_frames_after = with_frame_allocator(|frame_allocator| frame_allocator.free_frames());
+    assert_eq!(free_frames_after, free_frames_before);
+}
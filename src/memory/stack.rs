@@ -0,0 +1,75 @@
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr
+};
+use super::vmalloc::{self, VmallocError};
+
+/// A kernel stack with an unmapped guard page right below it, so an overflow page faults
+/// instead of silently running into whatever comes next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl KernelStack {
+    /// The address to load into the stack pointer (stacks grow downwards)
+    pub fn top(&self) -> VirtAddr {
+        self.end
+    }
+
+    /// The lowest usable address of the stack
+    pub fn bottom(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// The unmapped page below the stack
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.start) - 1
+    }
+}
+
+/// Maps a new stack of the given number of pages in the vmalloc range
+///
+/// vmalloc leaves the page below every region unmapped, which is what makes the guard page
+pub fn allocate_stack(pages: u64) -> Result<KernelStack, VmallocError> {
+    assert!(pages > 0);
+    let start = vmalloc::vmalloc((pages * 4096) as usize, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)?;
+    Ok(KernelStack {
+        start,
+        end: start + pages * 4096,
+    })
+}
+
+/// Unmaps the stack and gives its frames and virtual range back
+///
+/// Unsafe as the caller must guarantee that nothing runs on the stack anymore
+pub unsafe fn free_stack(stack: KernelStack) {
+    vmalloc::vfree(stack.start);
+}
+
+// Test that the stack is mapped and writable but the page below it isn't mapped
+#[test_case]
+fn test_stack_guard_page() {
+    use x86_64::structures::paging::Translate;
+
+    let stack = allocate_stack(2).unwrap();
+    assert_eq!(stack.top() - stack.bottom(), 2 * 4096);
+    assert_eq!(stack.guard_page().start_address() + 4096u64, stack.bottom());
+
+    let translate = |addr: VirtAddr| super::MAPPER.lock().as_ref().unwrap().translate_addr(addr);
+    assert!(translate(stack.guard_page().start_address()).is_none());
+    assert!(translate(stack.bottom()).is_some());
+    assert!(translate(stack.top() - 1u64).is_some());
+
+    // The whole stack is usable
+    let top: *mut u64 = (stack.top() - 8u64).as_mut_ptr();
+    let bottom: *mut u64 = stack.bottom().as_mut_ptr();
+    unsafe {
+        top.write_volatile(1);
+        bottom.write_volatile(2);
+        assert_eq!(top.read_volatile() + bottom.read_volatile(), 3);
+        free_stack(stack);
+    }
+    assert!(translate(stack.bottom()).is_none());
+}
@@ -0,0 +1,115 @@
+use core::fmt;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+};
+use crate::allocator::{self, HeapStats};
+use super::bitmap::{BitmapFrameAllocator, FrameStats};
+use super::{phys_to_virt, with_frame_allocator};
+
+/// A snapshot of the kernel's memory usage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub frames: FrameStats,
+    pub heap: HeapStats,
+    /// Frames holding the active page tables (including the level 4 table)
+    pub page_table_frames: usize,
+    /// The part of 'page_table_frames' that came from the frame allocator, the rest are the
+    /// bootloader's tables in reserved memory
+    pub allocated_page_table_frames: usize,
+}
+
+impl MemoryReport {
+    /// Frames from the frame allocator in use for anything but page tables
+    pub fn data_frames(&self) -> usize {
+        self.frames.in_use - self.allocated_page_table_frames
+    }
+
+    /// Whether everything allocated since 'before' was freed again
+    ///
+    /// Page tables created in between are allowed to stay, since unmapping never frees them
+    pub fn freed_everything_since(&self, before: &MemoryReport) -> bool {
+        self.data_frames() == before.data_frames() && self.heap.bytes_in_use == before.heap.bytes_in_use
+    }
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frames = &self.frames;
+        writeln!(
+            f,
+            "frames: {} of {} in use (peak {}), {} allocated, {} freed",
+            frames.in_use, frames.total, frames.peak_in_use, frames.allocations, frames.deallocations
+        )?;
+        writeln!(
+            f,
+            "page tables: {} frames ({} allocated by the kernel)",
+            self.page_table_frames, self.allocated_page_table_frames
+        )?;
+        let heap = &self.heap;
+        write!(
+            f,
+            "heap: {} of {} bytes in use (peak {}), {} allocated, {} freed, {} failed",
+            heap.bytes_in_use, heap.size, heap.peak_bytes_in_use, heap.allocations, heap.deallocations,
+            heap.failed_allocations
+        )
+    }
+}
+
+/// Collects the current counters of the frame allocator and the heap
+pub fn report() -> MemoryReport {
+    // Walk the tables with the allocator locked, so the counts match its stats
+    let (frames, (page_table_frames, allocated_page_table_frames)) = with_frame_allocator(|frame_allocator| {
+        (frame_allocator.stats(), count_page_tables(frame_allocator, Cr3::read().0, 4))
+    });
+    MemoryReport {
+        frames,
+        heap: allocator::stats(),
+        page_table_frames,
+        allocated_page_table_frames,
+    }
+}
+
+// Counts the table in the given frame and every table below it, both all of them and only the
+// ones owned by the frame allocator
+fn count_page_tables(frame_allocator: &BitmapFrameAllocator, frame: PhysFrame, level: u8) -> (usize, usize) {
+    let own = (1, frame_allocator.owns(frame) as usize);
+    if level == 1 {
+        return own;
+    }
+    let table = unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<PageTable>() };
+    table.iter()
+        .filter(|entry| !entry.flags().contains(PageTableFlags::HUGE_PAGE))
+        .filter_map(|entry| entry.frame().ok())
+        .map(|child| count_page_tables(frame_allocator, child, level - 1))
+        .fold(own, |(all, allocated), (child_all, child_allocated)| (all + child_all, allocated + child_allocated))
+}
+
+// Test that the counters follow allocations and frees
+#[test_case]
+fn test_report_counts() {
+    use alloc::vec::Vec;
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    let before = report();
+    assert!(before.page_table_frames >= 4);
+    // The heap's mapping needed tables the bootloader didn't have
+    assert!(before.allocated_page_table_frames > 0);
+    assert!(before.allocated_page_table_frames < before.page_table_frames);
+    assert_eq!(before.data_frames() + before.allocated_page_table_frames, before.frames.in_use);
+
+    let buffer: Vec<u8> = Vec::with_capacity(1000);
+    let frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame()).unwrap();
+    let during = report();
+    assert_eq!(during.heap.bytes_in_use, before.heap.bytes_in_use + 1000);
+    assert_eq!(during.heap.allocations, before.heap.allocations + 1);
+    assert_eq!(during.frames.in_use, before.frames.in_use + 1);
+    assert!(during.frames.peak_in_use >= during.frames.in_use);
+    assert!(!during.freed_everything_since(&before));
+
+    drop(buffer);
+    with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+    let after = report();
+    assert!(after.freed_everything_since(&before));
+    assert_eq!(after.frames.deallocations, before.frames.deallocations + 1);
+}
@@ -0,0 +1,165 @@
+use alloc::collections::BTreeMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr
+};
+use super::protection::is_writable_executable;
+use super::{phys_to_virt, with_mapper_and_allocator};
+
+/// A range of kernel virtual memory that is reserved up front and backed by zeroed frames on first access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualMemoryArea {
+    pub start: VirtAddr,
+    pub len: u64,
+    pub flags: PageTableFlags, // The flags pages in the area get mapped with ('PRESENT' is implied)
+}
+
+impl VirtualMemoryArea {
+    /// The first address after the area
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.len
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaError {
+    /// The start or length isn't a multiple of the page size (or the length is zero)
+    Unaligned,
+    /// The area overlaps an already registered one
+    Overlap,
+    /// No area starts at the given address
+    NotFound,
+    /// The flags ask for writable and executable memory at once
+    WritableExecutable,
+}
+
+lazy_static! {
+    // Every registered area, keyed by its start address
+    static ref AREAS: Mutex<BTreeMap<u64, VirtualMemoryArea>> = Mutex::new(BTreeMap::new());
+}
+
+/// Registers an area whose pages get mapped lazily when they're first touched
+pub fn reserve(start: VirtAddr, len: u64, flags: PageTableFlags) -> Result<VirtualMemoryArea, AreaError> {
+    if len == 0 || !start.is_aligned(4096u64) || len % 4096 != 0 {
+        return Err(AreaError::Unaligned);
+    }
+    if is_writable_executable(flags) {
+        return Err(AreaError::WritableExecutable);
+    }
+    let area = VirtualMemoryArea { start, len, flags };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        // Only the closest area below and the first one above can overlap
+        let below = areas.range(..area.end().as_u64()).next_back();
+        if below.is_some_and(|(_, other)| other.end() > area.start) {
+            return Err(AreaError::Overlap);
+        }
+        areas.insert(start.as_u64(), area);
+        Ok(area)
+    })
+}
+
+/// Removes the area starting at the given address, unmapping and freeing every page that was touched
+pub fn release(start: VirtAddr) -> Result<(), AreaError> {
+    let area = x86_64::instructions::interrupts::without_interrupts(|| AREAS.lock().remove(&start.as_u64()))
+        .ok_or(AreaError::NotFound)?;
+
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(area.start),
+        Page::containing_address(area.end()),
+    );
+    with_mapper_and_allocator(|mapper, frame_allocator| {
+        for page in pages {
+            // Pages that were never touched aren't mapped
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Returns the area containing the given address, if any
+pub fn find(addr: VirtAddr) -> Option<VirtualMemoryArea> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let areas = AREAS.lock();
+        areas.range(..=addr.as_u64()).next_back()
+            .map(|(_, area)| *area)
+            .filter(|area| area.contains(addr))
+    })
+}
+
+/// Tries to resolve a page fault by backing the faulting page with a zeroed frame
+///
+/// Returns false if the fault isn't a first access to a page in a registered area, in which
+/// case it's a real error
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // The page is already present, so this is an access rights problem
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let area = match find(addr) {
+        Some(area) => area,
+        None => return false,
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !area.flags.contains(PageTableFlags::WRITABLE) {
+        return false;
+    }
+
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let result = with_mapper_and_allocator(|mapper, frame_allocator| {
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        // Zero the frame through the physical memory mapping before anyone can see it
+        unsafe {
+            let frame_ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+            frame_ptr.write_bytes(0, 4096);
+        }
+        let flags = area.flags | PageTableFlags::PRESENT;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                Err(err)
+            }
+        }
+    });
+
+    match result {
+        Ok(()) => true,
+        // Someone else mapped the page in the meantime
+        Err(MapToError::PageAlreadyMapped(_)) => true,
+        Err(_) => false,
+    }
+}
+
+// Test that overlapping areas are rejected and areas can be released again
+#[test_case]
+fn test_reserve_overlap() {
+    let start = VirtAddr::new(0x_5555_1000_0000);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    assert_eq!(reserve(start, 4096, PageTableFlags::WRITABLE), Err(AreaError::WritableExecutable));
+    reserve(start, 4 * 4096, flags).unwrap();
+    assert_eq!(reserve(start + 3 * 4096u64, 4096, flags), Err(AreaError::Overlap));
+    assert_eq!(reserve(start - 4096u64, 2 * 4096, flags), Err(AreaError::Overlap));
+    assert_eq!(reserve(start + 1u64, 4096, flags), Err(AreaError::Unaligned));
+    assert!(find(start + 4095u64).is_some());
+    assert!(find(start + 4 * 4096u64).is_none());
+
+    // Directly next to the existing area is fine
+    reserve(start + 4 * 4096u64, 4096, flags).unwrap();
+    release(start).unwrap();
+    release(start + 4 * 4096u64).unwrap();
+    assert_eq!(release(start), Err(AreaError::NotFound));
+}
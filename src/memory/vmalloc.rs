@@ -0,0 +1,211 @@
+use alloc::collections::BTreeMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr
+};
+use super::protection::is_writable_executable;
+use super::with_mapper_and_allocator;
+
+// The virtual range handed out for kernel mappings that don't need a fixed address (stacks, MMIO, ...)
+pub const VMALLOC_START: u64 = 0x_6000_0000_0000;
+pub const VMALLOC_END: u64 = 0x_6100_0000_0000;
+
+#[derive(Debug)]
+pub enum VmallocError {
+    /// There's no free virtual range big enough
+    OutOfVirtualSpace,
+    /// Mapping the range failed (most likely out of physical frames)
+    Map(MapToError<Size4KiB>),
+    /// The flags ask for writable and executable memory at once
+    WritableExecutable,
+}
+
+lazy_static! {
+    // The free parts of the vmalloc range as start address => number of pages
+    static ref FREE_RANGES: Mutex<BTreeMap<u64, u64>> = {
+        let mut free = BTreeMap::new();
+        free.insert(VMALLOC_START, (VMALLOC_END - VMALLOC_START) / 4096);
+        Mutex::new(free)
+    };
+
+    // Regions mapped by 'vmalloc' as start address => number of mapped pages
+    static ref ALLOCATIONS: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+}
+
+/// Reserves a range of unmapped virtual pages without backing it
+///
+/// Useful when the caller maps the pages itself, e.g. to specific physical frames
+pub fn reserve(pages: u64) -> Option<Page> {
+    reserve_aligned(pages, 1)
+}
+
+/// Like 'reserve', but the range starts at a multiple of 'align' pages (e.g. for huge pages)
+pub fn reserve_aligned(pages: u64, align: u64) -> Option<Page> {
+    assert!(pages > 0 && align.is_power_of_two());
+    let align_bytes = align * 4096;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut free = FREE_RANGES.lock();
+        // Take the first range that's big enough once its start is aligned
+        let (start, len, aligned) = free.iter().find_map(|(&start, &len)| {
+            let aligned = start.next_multiple_of(align_bytes);
+            if start + len * 4096 >= aligned + pages * 4096 {
+                Some((start, len, aligned))
+            } else {
+                None
+            }
+        })?;
+        free.remove(&start);
+        // Whatever is left on either side stays free
+        if aligned > start {
+            free.insert(start, (aligned - start) / 4096);
+        }
+        let end = aligned + pages * 4096;
+        if start + len * 4096 > end {
+            free.insert(end, (start + len * 4096 - end) / 4096);
+        }
+        Some(Page::containing_address(VirtAddr::new(aligned)))
+    })
+}
+
+/// Gives a range from 'reserve' back, merging it with neighbouring free ranges
+///
+/// Unsafe as the caller must guarantee the range was reserved and nothing is mapped in it anymore
+pub unsafe fn release(start: Page, pages: u64) {
+    let mut start = start.start_address().as_u64();
+    let mut pages = pages;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut free = FREE_RANGES.lock();
+        // Merge with the free range right before this one
+        let before = free.range(..start).next_back().map(|(&s, &l)| (s, l));
+        if let Some((before_start, before_pages)) = before {
+            assert!(before_start + before_pages * 4096 <= start, "double release of {:#x}", start);
+            if before_start + before_pages * 4096 == start {
+                free.remove(&before_start);
+                start = before_start;
+                pages += before_pages;
+            }
+        }
+        // And with the one right after it
+        let end = start + pages * 4096;
+        if let Some(after_pages) = free.remove(&end) {
+            pages += after_pages;
+        }
+        free.insert(start, pages);
+    });
+}
+
+/// Maps 'size' bytes (rounded up to whole pages) of fresh frames at a free virtual address
+///
+/// The region is surrounded by unmapped guard pages, so running off either end page faults
+pub fn vmalloc(size: usize, flags: PageTableFlags) -> Result<VirtAddr, VmallocError> {
+    if is_writable_executable(flags) {
+        return Err(VmallocError::WritableExecutable);
+    }
+    let pages = size.div_ceil(4096) as u64;
+    let guard_page = reserve(pages + 2).ok_or(VmallocError::OutOfVirtualSpace)?;
+    let start = guard_page + 1;
+
+    let result = with_mapper_and_allocator(|mapper, frame_allocator| {
+        for page in Page::range(start, start + pages) {
+            let mapped = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)
+                .and_then(|frame| unsafe {
+                    mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, frame_allocator)
+                        .map_err(|err| {
+                            frame_allocator.deallocate_frame(frame);
+                            err
+                        })
+                });
+            match mapped {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    // Undo the pages mapped so far
+                    unmap_and_free(mapper, frame_allocator, start, page);
+                    return Err(VmallocError::Map(err));
+                }
+            }
+        }
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => {
+            x86_64::instructions::interrupts::without_interrupts(|| {
+                ALLOCATIONS.lock().insert(start.start_address().as_u64(), pages);
+            });
+            Ok(start.start_address())
+        }
+        Err(err) => {
+            unsafe { release(guard_page, pages + 2) };
+            Err(err)
+        }
+    }
+}
+
+/// Unmaps a region returned by 'vmalloc', freeing its frames and its virtual range
+///
+/// Unsafe as the caller must guarantee the region isn't used anymore
+pub unsafe fn vfree(addr: VirtAddr) {
+    let pages = x86_64::instructions::interrupts::without_interrupts(|| ALLOCATIONS.lock().remove(&addr.as_u64()))
+        .expect("vfree of an address that wasn't returned by vmalloc");
+    let start = Page::<Size4KiB>::containing_address(addr);
+
+    with_mapper_and_allocator(|mapper, frame_allocator| {
+        unmap_and_free(mapper, frame_allocator, start, start + pages);
+    });
+    release(start - 1, pages + 2);
+}
+
+// Unmaps the pages from 'start' up to (not including) 'end' and frees their frames
+fn unmap_and_free(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    start: Page,
+    end: Page,
+) {
+    for page in Page::range(start, end) {
+        let (frame, flush) = mapper.unmap(page).expect("vmalloc page wasn't mapped");
+        flush.flush();
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+// Test that regions don't overlap, are usable and give everything back when freed
+#[test_case]
+fn test_vmalloc_vfree() {
+    use super::with_frame_allocator;
+
+    let used_before = with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    assert!(matches!(vmalloc(4096, PageTableFlags::WRITABLE), Err(VmallocError::WritableExecutable)));
+    let a = vmalloc(3 * 4096, flags).unwrap();
+    let b = vmalloc(100, flags).unwrap();
+    assert!(a.as_u64() >= VMALLOC_START && b.as_u64() < VMALLOC_END);
+    // Each region has a guard page on both sides
+    assert!(b >= a + 5 * 4096u64 || b + 3 * 4096u64 <= a);
+
+    unsafe {
+        let a_ptr: *mut u8 = a.as_mut_ptr();
+        a_ptr.write_bytes(0x11, 3 * 4096);
+        let b_ptr: *mut u8 = b.as_mut_ptr();
+        b_ptr.write_bytes(0x22, 4096);
+        assert_eq!(*a_ptr.add(3 * 4096 - 1), 0x11);
+
+        vfree(a);
+        vfree(b);
+    }
+
+    // Page tables created along the way stay around, the mapped frames don't
+    let used_after = with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    assert!(used_after - used_before <= 3);
+
+    // The freed range is merged and handed out again
+    let c = vmalloc(3 * 4096, flags).unwrap();
+    assert_eq!(c, a.min(b));
+    unsafe { vfree(c) };
+}
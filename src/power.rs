@@ -0,0 +1,173 @@
+use core::convert::Infallible;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::acpi::{self, AcpiError, GenericAddress, Signature};
+
+// PM1 control register bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+// AML opcodes needed to read the '\_S5' package out of the DSDT
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+// The 8042 keyboard controller, whose command 0xFE pulses the CPU reset line
+const PS2_STATUS_PORT: u16 = 0x64;
+const PS2_INPUT_FULL: u8 = 1 << 1;
+const PS2_PULSE_RESET: u8 = 0xFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    NoFadt,
+    NoSleepState, // The DSDT has no usable '\_S5' object
+    Acpi(AcpiError),
+    StillRunning, // Everything was written but the machine didn't turn off
+}
+
+/// Turns the machine off through ACPI, putting it into the S5 sleep state
+///
+/// Only returns if that isn't possible
+pub fn shutdown() -> Result<Infallible, PowerError> {
+    let fadt = acpi::fadt().ok_or(PowerError::NoFadt)?;
+    let dsdt = acpi::table_bytes(fadt.dsdt).map_err(PowerError::Acpi)?;
+    if dsdt[..4] != Signature::DSDT.0 {
+        return Err(PowerError::Acpi(AcpiError::UnexpectedSignature(Signature([dsdt[0], dsdt[1], dsdt[2], dsdt[3]]))));
+    }
+    let (sleep_type_a, sleep_type_b) = find_sleep_type(dsdt, b"_S5_").ok_or(PowerError::NoSleepState)?;
+
+    // If the machine stays on, the caller gets back the interrupt state it called with
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe {
+            enable_acpi(fadt.smi_command_port, fadt.acpi_enable, fadt.pm1a_control_block);
+            Port::<u16>::new(fadt.pm1a_control_block as u16).write((sleep_type_a as u16) << SLP_TYP_SHIFT | SLP_EN);
+            if fadt.pm1b_control_block != 0 {
+                Port::<u16>::new(fadt.pm1b_control_block as u16).write((sleep_type_b as u16) << SLP_TYP_SHIFT | SLP_EN);
+            }
+        }
+        wait();
+    });
+    Err(PowerError::StillRunning)
+}
+
+/// Restarts the machine, through the ACPI reset register, the 8042 or a triple fault, whichever works first
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    if let Some(fadt) = acpi::fadt() {
+        if let Some(register) = fadt.reset_register {
+            unsafe { write_reset_register(register, fadt.reset_value) };
+            wait();
+        }
+    }
+
+    unsafe {
+        let mut status = Port::<u8>::new(PS2_STATUS_PORT);
+        for _ in 0..0x10000 {
+            if status.read() & PS2_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        status.write(PS2_PULSE_RESET);
+    }
+    wait();
+
+    triple_fault()
+}
+
+// Switches the chipset from legacy mode to ACPI mode, unless the firmware already did
+unsafe fn enable_acpi(smi_command_port: u32, acpi_enable: u8, pm1a_control_block: u32) {
+    let mut control = Port::<u16>::new(pm1a_control_block as u16);
+    if control.read() & SCI_EN != 0 || smi_command_port == 0 || acpi_enable == 0 {
+        return;
+    }
+    Port::<u8>::new(smi_command_port as u16).write(acpi_enable);
+    for _ in 0..0x100000 {
+        if control.read() & SCI_EN != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn write_reset_register(register: GenericAddress, value: u8) {
+    match register.address_space {
+        GenericAddress::SYSTEM_IO => Port::<u8>::new(register.address as u16).write(value),
+        GenericAddress::SYSTEM_MEMORY => {
+            use crate::memory::mmio::{map_mmio, CacheMode};
+            if let Ok(region) = map_mmio(PhysAddr::new(register.address), 1, CacheMode::Uncached) {
+                region.write::<u8>(0, value);
+            }
+        }
+        // PCI configuration space resets are left to the fallbacks
+        _ => {}
+    }
+}
+
+// Loads an empty IDT and raises an exception, which can't be delivered and resets the CPU
+fn triple_fault() -> ! {
+    use x86_64::{instructions::tables::lidt, structures::DescriptorTablePointer, VirtAddr};
+
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+    unsafe { lidt(&empty) };
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop();
+}
+
+// Gives the hardware a moment to react before trying something else
+fn wait() {
+    for _ in 0..10_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+// Finds the named sleep state package in the AML and returns its SLP_TYPa and SLP_TYPb values
+//
+// Doesn't run the AML, it expects the usual 'Name (_S5, Package () { a, b, ... })' with constant values
+fn find_sleep_type(aml: &[u8], name: &[u8; 4]) -> Option<(u8, u8)> {
+    let position = aml.windows(5).position(|w| &w[1..] == name && (w[0] == AML_NAME_OP || w[0] == b'\\'))?;
+    let mut bytes = &aml[position + 5..];
+    if bytes.first() != Some(&AML_PACKAGE_OP) {
+        return None;
+    }
+    // PkgLength, the top two bits of its first byte count the bytes that follow, then NumElements
+    let length_bytes = (*bytes.get(1)? >> 6) as usize;
+    bytes = bytes.get(2 + length_bytes + 1..)?;
+
+    let mut read_integer = || {
+        let (value, size) = match *bytes.first()? {
+            AML_ZERO_OP => (0, 1),
+            AML_ONE_OP => (1, 1),
+            AML_BYTE_PREFIX => (*bytes.get(1)?, 2),
+            _ => return None,
+        };
+        bytes = &bytes[size..];
+        Some(value)
+    };
+    let a = read_integer()?;
+    let b = read_integer()?;
+    Some((a, b))
+}
+
+// Test that the S5 package is found in QEMU's DSDT
+#[test_case]
+fn test_dsdt_has_s5() {
+    let fadt = acpi::fadt().expect("no FADT");
+    let dsdt = acpi::table_bytes(fadt.dsdt).unwrap();
+    assert!(find_sleep_type(dsdt, b"_S5_").is_some());
+}
+
+// Test that sleep types are read from the common encodings of the package
+#[test_case]
+fn test_find_sleep_type() {
+    // Name (_S5, Package (0x04) { 0x05, One, Zero, Zero }) behind some other bytes
+    let aml = [0x10, 0x42, AML_NAME_OP, b'_', b'S', b'5', b'_', AML_PACKAGE_OP, 0x07, 0x04,
+        AML_BYTE_PREFIX, 0x05, AML_ONE_OP, AML_ZERO_OP, AML_ZERO_OP];
+    assert_eq!(find_sleep_type(&aml, b"_S5_"), Some((5, 1)));
+    assert_eq!(find_sleep_type(&aml, b"_S4_"), None);
+    // A method instead of a package can't be evaluated here
+    assert_eq!(find_sleep_type(&[AML_NAME_OP, b'_', b'S', b'5', b'_', 0x14, 0x00], b"_S5_"), None);
+}
@@ -0,0 +1,248 @@
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::interrupts::irq;
+
+pub mod pit;
+pub mod hpet;
+pub mod tsc;
+pub mod rtc;
+
+/// How often the timer interrupt fires
+pub const TICK_HZ: u32 = 1000;
+
+/// How many timer callbacks can be pending at once
+pub const MAX_TIMERS: usize = 32;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// The real length of a tick, the PIT can't hit 'TICK_HZ' exactly
+static NANOS_PER_TICK: AtomicU64 = AtomicU64::new(NANOS_PER_SECOND / TICK_HZ as u64);
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What 'now' reads the time from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    Ticks, // The timer interrupt count, only as precise as a tick
+    Hpet,
+    Tsc,   // Only used when it's invariant
+}
+
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Ticks as u8);
+// The clock source's reading and the uptime when it was picked, so 'now' carries on from the ticks
+static CLOCK_BASE: AtomicU64 = AtomicU64::new(0);
+static CLOCK_OFFSET_NANOS: AtomicU64 = AtomicU64::new(0);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+// The RTC's time at boot as a Unix timestamp, and 'now' when it was read
+static BOOT_UNIX_SECONDS: AtomicU64 = AtomicU64::new(0);
+static BOOT_NOW_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a registered timer callback, for cancelling it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    id: TimerId,
+    deadline: u64, // The tick it's due at
+    period: u64,   // Ticks between runs, 0 for one-shot timers
+    callback: fn(),
+}
+
+// Pending callbacks, in a fixed table as the timer interrupt can't use the heap
+static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+
+/// Programs the PIT to tick at 'TICK_HZ', registers its IRQ handler and picks the clock source for 'now'
+///
+/// The ticks start once interrupts are enabled. Needs the ACPI tables to find the HPET
+pub fn init() {
+    let divisor = pit::set_frequency(TICK_HZ);
+    NANOS_PER_TICK.store(divisor as u64 * NANOS_PER_SECOND / pit::FREQUENCY as u64, Ordering::SeqCst);
+    irq::register(pit::IRQ, timer_irq_handler, core::ptr::null_mut()).expect("timer IRQ taken");
+
+    let has_hpet = hpet::init();
+    let source = if tsc::is_invariant() {
+        TSC_HZ.store(tsc::calibrate(), Ordering::SeqCst);
+        ClockSource::Tsc
+    } else if has_hpet && hpet::with_hpet(|hpet| hpet.is_64_bit()) == Some(true) {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Ticks
+    };
+    set_clock_source(source);
+
+    BOOT_UNIX_SECONDS.store(rtc::read().unix_timestamp(), Ordering::SeqCst);
+    BOOT_NOW_NANOS.store(now().as_nanos() as u64, Ordering::SeqCst);
+}
+
+/// The clock source 'now' reads from
+pub fn clock_source() -> ClockSource {
+    match CLOCK_SOURCE.load(Ordering::SeqCst) {
+        x if x == ClockSource::Hpet as u8 => ClockSource::Hpet,
+        x if x == ClockSource::Tsc as u8 => ClockSource::Tsc,
+        _ => ClockSource::Ticks,
+    }
+}
+
+/// The TSC frequency in Hz, 0 if it wasn't calibrated because it isn't invariant
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Ordering::SeqCst)
+}
+
+/// The time since the timer started ticking, in nanosecond resolution unless only the ticks are available
+///
+/// Cheap enough for profiling, it never goes backwards
+pub fn now() -> Duration {
+    let base = CLOCK_BASE.load(Ordering::SeqCst);
+    let offset = CLOCK_OFFSET_NANOS.load(Ordering::SeqCst);
+    let nanos = match clock_source() {
+        ClockSource::Ticks => return uptime(),
+        ClockSource::Hpet => hpet::with_hpet(|hpet| hpet.to_nanos(hpet.counter() - base)).unwrap_or(0),
+        ClockSource::Tsc => {
+            ((tsc::read() - base) as u128 * NANOS_PER_SECOND as u128 / TSC_HZ.load(Ordering::SeqCst) as u128) as u64
+        }
+    };
+    Duration::from_nanos(offset + nanos)
+}
+
+/// The time since 1970-01-01 00:00:00, the RTC's reading at boot moved on by 'now'
+pub fn unix_time() -> Duration {
+    let since_boot = now() - Duration::from_nanos(BOOT_NOW_NANOS.load(Ordering::SeqCst));
+    Duration::from_secs(BOOT_UNIX_SECONDS.load(Ordering::SeqCst)) + since_boot
+}
+
+/// The current date and time, in the RTC's time zone
+pub fn wall_clock() -> rtc::DateTime {
+    rtc::DateTime::from_unix_timestamp(unix_time().as_secs())
+}
+
+// Switches 'now' over to the source, continuing from the current uptime
+fn set_clock_source(source: ClockSource) {
+    let base = match source {
+        ClockSource::Ticks => 0,
+        ClockSource::Hpet => hpet::with_hpet(|hpet| hpet.counter()).expect("HPET not started"),
+        ClockSource::Tsc => tsc::read(),
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        CLOCK_BASE.store(base, Ordering::SeqCst);
+        CLOCK_OFFSET_NANOS.store(uptime().as_nanos() as u64, Ordering::SeqCst);
+        CLOCK_SOURCE.store(source as u8, Ordering::SeqCst);
+    });
+}
+
+// Advances the clock and runs the timer callbacks that are due
+fn timer_irq_handler(_irq: u8, _context: *mut ()) -> bool {
+    tick();
+    true
+}
+
+// Counts a tick and runs the callbacks that are due
+fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+
+    // Collect the due callbacks first, so they're free to add or cancel timers
+    let mut due: [Option<fn()>; MAX_TIMERS] = [None; MAX_TIMERS];
+    {
+        let mut timers = TIMERS.lock();
+        for (slot, due) in timers.iter_mut().zip(due.iter_mut()) {
+            if let Some(timer) = slot {
+                if timer.deadline <= now {
+                    *due = Some(timer.callback);
+                    if timer.period == 0 {
+                        *slot = None;
+                    } else {
+                        timer.deadline = now + timer.period;
+                    }
+                }
+            }
+        }
+    }
+    for callback in due.iter().flatten() {
+        callback();
+    }
+}
+
+/// The number of timer ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// The time since the timer started ticking, in tick resolution
+pub fn uptime() -> Duration {
+    Duration::from_nanos(ticks() * NANOS_PER_TICK.load(Ordering::Relaxed))
+}
+
+/// The number of ticks covering at least 'duration'
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos_per_tick = NANOS_PER_TICK.load(Ordering::Relaxed) as u128;
+    duration.as_nanos().div_ceil(nanos_per_tick) as u64
+}
+
+/// Halts until at least 'duration' has passed, needs interrupts to be enabled
+pub fn sleep(duration: Duration) {
+    assert!(x86_64::instructions::interrupts::are_enabled(), "sleeping with interrupts disabled");
+    let deadline = ticks() + duration_to_ticks(duration);
+    while ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Spins until at least 'duration' has passed, for code that mustn't halt the CPU
+pub fn busy_sleep(duration: Duration) {
+    let deadline = ticks() + duration_to_ticks(duration);
+    while ticks() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs the callback once after 'delay', from the timer interrupt
+///
+/// Returns 'None' if 'MAX_TIMERS' callbacks are already pending
+pub fn after(delay: Duration, callback: fn()) -> Option<TimerId> {
+    add_timer(duration_to_ticks(delay).max(1), 0, callback)
+}
+
+/// Runs the callback every 'period' from the timer interrupt, until it's cancelled
+///
+/// Returns 'None' if 'MAX_TIMERS' callbacks are already pending
+pub fn every(period: Duration, callback: fn()) -> Option<TimerId> {
+    let period = duration_to_ticks(period).max(1);
+    add_timer(period, period, callback)
+}
+
+/// Stops a pending callback, returns false if it already ran or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        match timers.iter_mut().find(|slot| slot.is_some_and(|timer| timer.id == id)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+fn add_timer(delay: u64, period: u64, callback: fn()) -> Option<TimerId> {
+    let id = TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let slot = timers.iter_mut().find(|slot| slot.is_none())?;
+        *slot = Some(Timer { id, deadline: ticks() + delay, period, callback });
+        Some(id)
+    })
+}
+
+// Test that durations round up to whole ticks
+#[test_case]
+fn test_duration_to_ticks() {
+    let tick = Duration::from_nanos(NANOS_PER_TICK.load(Ordering::Relaxed));
+    assert_eq!(duration_to_ticks(Duration::from_secs(0)), 0);
+    assert_eq!(duration_to_ticks(tick), 1);
+    assert_eq!(duration_to_ticks(tick + Duration::from_nanos(1)), 2);
+    assert_eq!(duration_to_ticks(tick * 250), 250);
+}
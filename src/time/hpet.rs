@@ -0,0 +1,90 @@
+use spin::Mutex;
+use x86_64::PhysAddr;
+use crate::acpi::{self, GenericAddress};
+use crate::memory::mmio::{map_mmio, CacheMode, MmioRegion};
+
+// Registers of the general block (byte offsets)
+const CAPABILITIES: usize = 0x00;
+const CONFIGURATION: usize = 0x10;
+const MAIN_COUNTER: usize = 0xF0;
+const REGISTERS_SIZE: usize = 0x400;
+
+const COUNTER_IS_64_BIT: u64 = 1 << 13;
+const ENABLE: u64 = 1 << 0;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+/// The HPET's main counter, the timers it also has aren't used
+pub struct Hpet {
+    region: MmioRegion,
+    period: u64, // Femtoseconds per counter increment
+    is_64_bit: bool,
+}
+
+impl Hpet {
+    /// Maps the HPET at 'base' and starts its main counter
+    ///
+    /// Unsafe as an HPET has to be at that address
+    pub unsafe fn new(base: PhysAddr) -> Option<Self> {
+        let region = map_mmio(base, REGISTERS_SIZE, CacheMode::Uncached).ok()?;
+        let capabilities: u64 = region.read(CAPABILITIES);
+        let period = capabilities >> 32;
+        // The spec caps the period at 100ns, anything else means there's no HPET there
+        if period == 0 || period > 100 * FEMTOS_PER_NANO {
+            return None;
+        }
+        let configuration: u64 = region.read(CONFIGURATION);
+        region.write(CONFIGURATION, configuration | ENABLE);
+        Some(Hpet { region, period, is_64_bit: capabilities & COUNTER_IS_64_BIT != 0 })
+    }
+
+    /// The current value of the main counter
+    pub fn counter(&self) -> u64 {
+        self.region.read(MAIN_COUNTER)
+    }
+
+    /// Femtoseconds per counter increment
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+
+    /// Whether the main counter is 64 bits wide, a 32 bit one wraps after minutes
+    pub fn is_64_bit(&self) -> bool {
+        self.is_64_bit
+    }
+
+    /// Converts a number of counter increments to nanoseconds
+    pub fn to_nanos(&self, counts: u64) -> u64 {
+        (counts as u128 * self.period as u128 / FEMTOS_PER_NANO as u128) as u64
+    }
+}
+
+pub static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+
+/// Starts the HPET described by the ACPI tables, returns false if there isn't a usable one
+pub fn init() -> bool {
+    let base = match acpi::hpet() {
+        Some(hpet) if hpet.base_address.address_space == GenericAddress::SYSTEM_MEMORY => hpet.base_address.address,
+        _ => return false,
+    };
+    let hpet = match unsafe { Hpet::new(PhysAddr::new(base)) } {
+        Some(hpet) => hpet,
+        None => return false,
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| *HPET.lock() = Some(hpet));
+    true
+}
+
+/// Runs the closure with the HPET, if it was started
+pub fn with_hpet<R>(f: impl FnOnce(&Hpet) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| HPET.lock().as_ref().map(f))
+}
+
+// Test that QEMU's HPET is found and counts at a plausible rate
+#[test_case]
+fn test_hpet_counts() {
+    let (start, period) = with_hpet(|hpet| (hpet.counter(), hpet.period())).expect("HPET not started");
+    super::pit::wait_cycles(super::pit::FREQUENCY as u64 / 100); // 10ms
+    let elapsed = with_hpet(|hpet| hpet.to_nanos(hpet.counter() - start)).unwrap();
+    assert!(period <= 100 * FEMTOS_PER_NANO);
+    assert!(elapsed > 9_000_000 && elapsed < 11_000_000, "{}ns in 10ms", elapsed);
+}
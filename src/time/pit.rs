@@ -0,0 +1,80 @@
+use x86_64::instructions::port::Port;
+
+/// The frequency the PIT counts down at
+pub const FREQUENCY: u32 = 1_193_182;
+
+/// The IRQ line channel 0 raises
+pub const IRQ: u8 = 0;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// Port B of the old keyboard controller gates channel 2 and shows its output
+const PORT_B: u16 = 0x61;
+const CHANNEL_2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+
+// Command bits: the channel in 6-7, low then high byte in 4-5, the mode in 1-3
+const SELECT_CHANNEL_0: u8 = 0b00 << 6;
+const SELECT_CHANNEL_2: u8 = 0b10 << 6;
+const ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+const MODE_INTERRUPT_ON_COUNT: u8 = 0b000 << 1;
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+/// Makes channel 0 raise IRQ 0 periodically at about 'hz', returns the divisor used
+pub fn set_frequency(hz: u32) -> u16 {
+    let divisor = divisor_for(hz);
+    unsafe {
+        Port::<u8>::new(COMMAND).write(SELECT_CHANNEL_0 | ACCESS_LOW_HIGH | MODE_RATE_GENERATOR);
+        let mut data = Port::<u8>::new(CHANNEL_0);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+    divisor
+}
+
+// The divisor closest to the frequency, clamped to what the 16 bit counter can do (0 means 65536)
+fn divisor_for(hz: u32) -> u16 {
+    let divisor = (FREQUENCY + hz / 2) / hz.max(1);
+    divisor.max(1).min(u16::MAX as u32) as u16
+}
+
+/// Busy waits for 'count' PIT cycles on channel 2, without using interrupts or channel 0
+///
+/// Meant for calibrating other clocks against, it doesn't disturb the periodic tick
+pub fn wait_cycles(mut count: u64) {
+    while count > 0 {
+        let chunk = count.min(u16::MAX as u64) as u16;
+        unsafe { wait_once(chunk) };
+        count -= chunk as u64;
+    }
+}
+
+// Counts 'count' cycles down in mode 0, whose output goes high when the count runs out
+unsafe fn wait_once(count: u16) {
+    let mut port_b = Port::<u8>::new(PORT_B);
+    // Gate off while programming, and keep the speaker quiet
+    let b = port_b.read() & !(CHANNEL_2_GATE | SPEAKER_ENABLE);
+    port_b.write(b);
+
+    Port::<u8>::new(COMMAND).write(SELECT_CHANNEL_2 | ACCESS_LOW_HIGH | MODE_INTERRUPT_ON_COUNT);
+    let mut data = Port::<u8>::new(CHANNEL_2);
+    data.write(count as u8);
+    data.write((count >> 8) as u8);
+
+    port_b.write(b | CHANNEL_2_GATE);
+    while port_b.read() & CHANNEL_2_OUTPUT == 0 {
+        core::hint::spin_loop();
+    }
+    port_b.write(b);
+}
+
+// Test that the divisor rounds to the nearest frequency and stays in range
+#[test_case]
+fn test_divisor_for() {
+    assert_eq!(divisor_for(1000), 1193);
+    assert_eq!(divisor_for(100), 11932);
+    assert_eq!(divisor_for(1), u16::MAX);
+    assert_eq!(divisor_for(FREQUENCY * 2), 1);
+}
@@ -0,0 +1,250 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::acpi;
+use crate::interrupts::irq::{self, HandlerId};
+
+// The CMOS is read through a register select and a data port, bit 7 of the select disables NMIs
+const CMOS_SELECT: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const NMI_DISABLE: u8 = 1 << 7;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7; // Status A
+const RATE_MASK: u8 = 0x0F;            // Status A
+const HOURS_24: u8 = 1 << 1;           // Status B
+const BINARY: u8 = 1 << 2;             // Status B
+const PERIODIC_INTERRUPT: u8 = 1 << 6; // Status B
+const HOUR_PM: u8 = 1 << 7;
+
+// The periodic interrupt divides this by 2^(rate - 1)
+const BASE_FREQUENCY: u32 = 32768;
+
+/// The IRQ line the RTC raises
+pub const IRQ: u8 = 8;
+
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static HANDLER: Mutex<Option<HandlerId>> = Mutex::new(None);
+
+/// A calendar date and time, in whatever time zone the RTC is set to (UTC on QEMU)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00
+    pub fn unix_timestamp(&self) -> u64 {
+        // Days from the civil date, counting years from March so the leap day comes last
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// The date and time 'timestamp' seconds after 1970-01-01 00:00:00
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let (days, seconds) = ((timestamp / 86400) as i64 + 719_468, timestamp % 86400);
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * month_index + 2) / 5 + 1) as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// The raw register values, before any BCD or 12 hour conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+/// Reads the current date and time from the RTC
+pub fn read() -> DateTime {
+    let century_register = acpi::fadt().map_or(0, |fadt| fadt.century_register);
+    let status_b = x86_64::instructions::interrupts::without_interrupts(|| unsafe { read_register(STATUS_B) });
+
+    // An update can happen between reading two registers, so read until two reads agree
+    let mut registers = read_registers(century_register);
+    loop {
+        let again = read_registers(century_register);
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+    convert(registers, status_b)
+}
+
+fn read_registers(century_register: u8) -> Registers {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        Registers {
+            second: read_register(SECONDS),
+            minute: read_register(MINUTES),
+            hour: read_register(HOURS),
+            day: read_register(DAY),
+            month: read_register(MONTH),
+            year: read_register(YEAR),
+            century: if century_register != 0 { read_register(century_register) } else { 0 },
+        }
+    })
+}
+
+// Turns the registers into a date, following the data format in status register B
+fn convert(registers: Registers, status_b: u8) -> DateTime {
+    let value = |raw: u8| if status_b & BINARY != 0 { raw } else { (raw >> 4) * 10 + (raw & 0x0F) };
+
+    // The PM flag sits in the hour's top bit, whatever the format of the rest
+    let mut hour = value(registers.hour & !HOUR_PM);
+    if status_b & HOURS_24 == 0 {
+        hour %= 12;
+        if registers.hour & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    // Without a century register, assume this century
+    let century = if registers.century != 0 { value(registers.century) as u16 } else { 20 };
+
+    DateTime {
+        year: century * 100 + value(registers.year) as u16,
+        month: value(registers.month),
+        day: value(registers.day),
+        hour,
+        minute: value(registers.minute),
+        second: value(registers.second),
+    }
+}
+
+/// Turns on the periodic interrupt on IRQ 8 at 32768 >> (rate - 1) Hz, for a rate of 3 to 15
+///
+/// Returns the resulting frequency
+pub fn enable_periodic_interrupt(rate: u8) -> u32 {
+    assert!((3..=15).contains(&rate), "RTC rate {} out of range", rate);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut handler = HANDLER.lock();
+        if handler.is_none() {
+            *handler = Some(irq::register(IRQ, handle_interrupt, core::ptr::null_mut()).expect("RTC IRQ taken"));
+        }
+        let status_a = read_register(STATUS_A);
+        write_register(STATUS_A, (status_a & !RATE_MASK) | rate);
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b | PERIODIC_INTERRUPT);
+        // A pending interrupt that's never acknowledged would block the next ones
+        read_register(STATUS_C);
+    });
+    BASE_FREQUENCY >> (rate - 1)
+}
+
+/// Turns the periodic interrupt off again
+pub fn disable_periodic_interrupt() {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+        if let Some(handler) = HANDLER.lock().take() {
+            irq::unregister(handler);
+        }
+    });
+}
+
+// Acknowledges the interrupt and counts it if it's the periodic one
+fn handle_interrupt(_irq: u8, _context: *mut ()) -> bool {
+    // The RTC raises no more interrupts until status register C is read
+    let cause = unsafe { read_register(STATUS_C) };
+    if cause & PERIODIC_INTERRUPT != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+    cause & PERIODIC_INTERRUPT != 0
+}
+
+/// The number of periodic interrupts since boot
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::SeqCst)
+}
+
+// Unsafe as writing the wrong CMOS register can corrupt the firmware settings
+unsafe fn read_register(register: u8) -> u8 {
+    Port::<u8>::new(CMOS_SELECT).write(register | NMI_DISABLE);
+    let value = Port::<u8>::new(CMOS_DATA).read();
+    Port::<u8>::new(CMOS_SELECT).write(0);
+    value
+}
+
+unsafe fn write_register(register: u8, value: u8) {
+    Port::<u8>::new(CMOS_SELECT).write(register | NMI_DISABLE);
+    Port::<u8>::new(CMOS_DATA).write(value);
+    Port::<u8>::new(CMOS_SELECT).write(0);
+}
+
+// Test that BCD and 12 hour values are converted
+#[test_case]
+fn test_convert() {
+    // 2024-02-29 11:05:09 PM, in BCD and 12 hour format
+    let registers = Registers { second: 0x09, minute: 0x05, hour: HOUR_PM | 0x11, day: 0x29, month: 0x02, year: 0x24, century: 0x20 };
+    let expected = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 5, second: 9 };
+    assert_eq!(convert(registers, 0), expected);
+
+    // 12 AM is midnight, in binary this time
+    let registers = Registers { second: 0, minute: 0, hour: 12, day: 1, month: 1, year: 99, century: 0 };
+    assert_eq!(convert(registers, BINARY).hour, 0);
+    assert_eq!(convert(registers, BINARY).year, 2099);
+    assert_eq!(convert(Registers { hour: 12 | HOUR_PM, ..registers }, BINARY).hour, 12);
+}
+
+// Test that dates round trip through Unix timestamps
+#[test_case]
+fn test_unix_timestamp() {
+    let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(epoch.unix_timestamp(), 0);
+    let leap_day = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 5, second: 9 };
+    assert_eq!(leap_day.unix_timestamp(), 1_709_247_909);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_247_909), leap_day);
+    assert_eq!(DateTime::from_unix_timestamp(0), epoch);
+}
@@ -0,0 +1,39 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use super::{hpet, pit};
+
+// How long calibration measures for
+const CALIBRATION_MILLIS: u64 = 50;
+
+/// Whether the TSC runs at a constant rate in every power state (CPUID 0x8000_0007, EDX bit 8)
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Reads the time stamp counter
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Measures the TSC frequency in Hz, against the HPET if it was started and the PIT otherwise
+pub fn calibrate() -> u64 {
+    let calibrated = x86_64::instructions::interrupts::without_interrupts(|| {
+        hpet::with_hpet(|hpet| {
+            let counts = CALIBRATION_MILLIS * 1_000_000_000_000 / hpet.period();
+            let (hpet_start, tsc_start) = (hpet.counter(), read());
+            while hpet.counter() - hpet_start < counts {
+                core::hint::spin_loop();
+            }
+            let (hpet_end, tsc_end) = (hpet.counter(), read());
+            (tsc_end - tsc_start) as u128 * 1_000_000_000 / hpet.to_nanos(hpet_end - hpet_start) as u128
+        })
+    });
+    match calibrated {
+        Some(hz) => hz as u64,
+        None => {
+            let start = read();
+            pit::wait_cycles(pit::FREQUENCY as u64 * CALIBRATION_MILLIS / 1000);
+            (read() - start) * 1000 / CALIBRATION_MILLIS
+        }
+    }
+}
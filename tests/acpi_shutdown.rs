@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+// Powers QEMU off through ACPI, the way a real machine would be turned off
+//
+// A successful shutdown ends QEMU with exit status 0 and never prints '[ok]'. bootimage only
+// maps 'test-success-exit-code' to 0 and passes every other status on as is, so 0 counts as a
+// pass as well. A shutdown that doesn't turn the machine off fails through the debug exit port
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rustos::power;
+use rustos::{exit_qemu, QemuExitCode, serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("acpi_shutdown::shutdown_powers_off...\t");
+
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    let err = match power::shutdown() {
+        Ok(never) => match never {},
+        Err(err) => err,
+    };
+    serial_println!("[failed]\n");
+    serial_println!("Error: execution continued after the shutdown ({:?})\n", err);
+    exit_qemu(QemuExitCode::Failed);
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
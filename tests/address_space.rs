@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use x86_64::{structures::paging::{Page, PageTableFlags}, VirtAddr};
+use rustos::memory::{self, address_space::{self, AddressSpace}};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+fn used_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.used_frames())
+}
+
+// Test that the same user address holds different data in two address spaces, with the kernel mapped in both
+#[test_case]
+fn switch_between_spaces() {
+    let addr = VirtAddr::new(address_space::USER_START + 0x1000);
+    let page = Page::containing_address(addr);
+    let value: *mut u64 = addr.as_mut_ptr();
+
+    let mut first = AddressSpace::new().unwrap();
+    let mut second = AddressSpace::new().unwrap();
+    first.map(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+    second.map(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+
+    unsafe {
+        first.activate();
+        assert!(first.is_active());
+        value.write_volatile(1);
+
+        second.activate();
+        assert_eq!(value.read_volatile(), 0);
+        value.write_volatile(2);
+
+        first.activate();
+        assert_eq!(value.read_volatile(), 1);
+        address_space::activate_kernel();
+    }
+    assert!(!first.is_active() && !second.is_active());
+}
+
+// Test that dropping an address space gives back its frames and page tables
+#[test_case]
+fn teardown_frees_everything() {
+    let used_before = used_frames();
+    let mut space = AddressSpace::new().unwrap();
+    // Spread the pages out so several intermediate tables are needed
+    for i in 0..4u64 {
+        let addr = VirtAddr::new(address_space::USER_START + i * 0x4000_0000 + i * 0x1000);
+        space.map(Page::containing_address(addr), PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+    }
+    assert!(used_frames() > used_before + 4);
+
+    drop(space);
+    assert_eq!(used_frames(), used_before);
+}
+
+// Test that kernel mappings made while another address space is active are visible in it
+#[test_case]
+fn kernel_mappings_stay_shared() {
+    use rustos::memory::vmalloc;
+
+    let space = AddressSpace::new().unwrap();
+    unsafe { space.activate() };
+    let addr = vmalloc::vmalloc(4096, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(42);
+        address_space::activate_kernel();
+        assert_eq!(ptr.read_volatile(), 42);
+        vmalloc::vfree(addr);
+    }
+}
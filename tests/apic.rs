@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rustos::{apic, interrupts::{self, Controller}, time};
+
+entry_point!(main);
+
+// QEMU's CPUs have an APIC, so the default setup should switch to it
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Test that the APIC took over from the PICs
+#[test_case]
+fn uses_apic() {
+    assert!(apic::is_supported());
+    assert_eq!(interrupts::controller(), Controller::Apic);
+    assert!(apic::LOCAL_APIC.lock().is_some());
+}
+
+// Test that the timer keeps firing through the I/O APIC, which only works if the EOIs arrive
+#[test_case]
+fn timer_ticks_through_apic() {
+    let start = time::ticks();
+    while time::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use x86_64::{structures::paging::{Page, PageTableFlags}, VirtAddr};
+use rustos::memory::{self, address_space::{self, AddressSpace}, cow};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+fn used_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.used_frames())
+}
+
+// Test that writes after cloning are private to the address space doing them
+#[test_case]
+fn writes_are_private() {
+    let addr = VirtAddr::new(address_space::USER_START);
+    let page = Page::containing_address(addr);
+    let value: *mut u64 = addr.as_mut_ptr();
+
+    let mut parent = AddressSpace::new().unwrap();
+    let frame = parent.map(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+    unsafe {
+        parent.activate();
+        value.write_volatile(1);
+        address_space::activate_kernel();
+    }
+
+    let child = parent.clone_cow().unwrap();
+    // Both map the same frame read-only until someone writes
+    assert_eq!(cow::ref_count(frame), 2);
+    let (child_frame, flags) = child.translate_page(page).unwrap();
+    assert_eq!(child_frame, frame);
+    assert!(flags.contains(cow::COPY_ON_WRITE) && !flags.contains(PageTableFlags::WRITABLE));
+
+    unsafe {
+        child.activate();
+        assert_eq!(value.read_volatile(), 1);
+        value.write_volatile(2);
+
+        parent.activate();
+        assert_eq!(value.read_volatile(), 1);
+        // The parent is the last user of the original frame, so it just gets it back writable
+        value.write_volatile(3);
+
+        child.activate();
+        assert_eq!(value.read_volatile(), 2);
+        address_space::activate_kernel();
+    }
+
+    assert_eq!(parent.translate_page(page).unwrap().0, frame);
+    assert_ne!(child.translate_page(page).unwrap().0, frame);
+    assert_eq!(cow::ref_count(frame), 1);
+}
+
+// Test that shared frames are freed once, when the last address space using them goes away
+#[test_case]
+fn shared_frames_freed_once() {
+    let used_before = used_frames();
+    let mut parent = AddressSpace::new().unwrap();
+    for i in 0..4u64 {
+        let page = Page::containing_address(VirtAddr::new(address_space::USER_START + i * 4096));
+        parent.map(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+    }
+    let child = parent.clone_cow().unwrap();
+
+    drop(parent);
+    // The child still sees its pages
+    let page = Page::containing_address(VirtAddr::new(address_space::USER_START));
+    assert!(child.translate_page(page).is_some());
+    drop(child);
+    assert_eq!(used_frames(), used_before);
+}
@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use x86_64::{structures::paging::{PageTableFlags, Translate}, VirtAddr};
+use rustos::memory::{self, vma};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Checks whether the given address currently has a page mapped behind it
+fn is_mapped(addr: VirtAddr) -> bool {
+    let mapper = memory::MAPPER.lock();
+    mapper.as_ref().unwrap().translate_addr(addr).is_some()
+}
+
+// Test that a lazily backed area reads as zeros, keeps writes and only takes frames for touched pages
+#[test_case]
+fn lazily_backed_area() {
+    let start = VirtAddr::new(0x_5555_0000_0000);
+    let pages = 8;
+    vma::reserve(start, pages * 4096, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+    assert!(!is_mapped(start));
+
+    let used_before = memory::with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    let area: *mut u64 = start.as_mut_ptr();
+    let words_per_page = 4096 / 8;
+
+    // Touch every other page
+    for page in (0..pages as usize).step_by(2) {
+        let word = unsafe { area.add(page * words_per_page) };
+        assert_eq!(unsafe { word.read_volatile() }, 0);
+        unsafe { word.write_volatile(0xdead_beef_0000 + page as u64) };
+    }
+    for page in (0..pages as usize).step_by(2) {
+        let word = unsafe { area.add(page * words_per_page) };
+        assert_eq!(unsafe { word.read_volatile() }, 0xdead_beef_0000 + page as u64);
+        // The rest of the page is still zeroed
+        assert_eq!(unsafe { word.add(words_per_page - 1).read_volatile() }, 0);
+    }
+    assert!(is_mapped(start));
+    assert!(!is_mapped(start + 4096u64));
+
+    // One frame per touched page, plus possibly some for new page tables
+    let used_after = memory::with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    assert!(used_after - used_before >= pages as usize / 2);
+    assert!(used_after - used_before <= pages as usize / 2 + 3);
+
+    vma::release(start).unwrap();
+    assert!(!is_mapped(start));
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rustos::interrupts::exceptions::{self, CrashReport, Exception};
+use rustos::{exit_qemu, QemuExitCode, serial_print, serial_println};
+
+// Called by the kernel's handler once it printed the report, instead of halting
+fn check_report(report: &CrashReport) {
+    if report.exception == Exception::DIVIDE_ERROR {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("unexpected exception: {}", report.exception.name);
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("divide_error::report_division_by_zero...\t");
+
+    rustos::init_memory(boot_info);
+    rustos::init();
+    exceptions::set_crash_hook(check_report);
+
+    // A division by 0 in Rust is a panic or undefined behaviour, so emit the 'div' by hand
+    let quotient: u64;
+    unsafe {
+        core::arch::asm!("div {}", in(reg) 0u64, inout("rax") 42u64 => quotient, inout("rdx") 0u64 => _, options(nomem, nostack));
+    }
+    serial_println!("42 / 0 = {}", quotient);
+
+    serial_println!("[failed]");
+    panic!("Execution continued after the exception");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
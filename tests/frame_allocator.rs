@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+use rustos::memory::with_frame_allocator;
+
+entry_point!(main);
+
+// The kernel's own bitmap allocator is the one under test
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Test that the counters add up after building the bitmap
+#[test_case]
+fn frame_counts() {
+    with_frame_allocator(|allocator| {
+        assert!(allocator.total_frames() > 0);
+        assert!(allocator.used_frames() > 0); // The bitmap itself takes up frames
+        assert_eq!(allocator.used_frames() + allocator.free_frames(), allocator.total_frames());
+    });
+}
+
+// Test that allocated frames are unique and counted as used
+#[test_case]
+fn allocate_distinct_frames() {
+    with_frame_allocator(|allocator| {
+        let used_before = allocator.used_frames();
+
+        let mut frames = [None; 100];
+        for slot in frames.iter_mut() {
+            *slot = Some(allocator.allocate_frame().expect("out of frames"));
+        }
+        for (i, a) in frames.iter().enumerate() {
+            for b in &frames[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        assert_eq!(allocator.used_frames(), used_before + frames.len());
+
+        for frame in frames.iter() {
+            unsafe { allocator.deallocate_frame(frame.unwrap()) };
+        }
+        assert_eq!(allocator.used_frames(), used_before);
+    });
+}
+
+// Test that a freed frame is handed out again
+#[test_case]
+fn reuse_freed_frame() {
+    with_frame_allocator(|allocator| {
+        let frame = allocator.allocate_frame().expect("out of frames");
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.allocate_frame(), Some(frame));
+        unsafe { allocator.deallocate_frame(frame) };
+    });
+}
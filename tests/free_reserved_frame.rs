@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use bootloader::bootinfo::MemoryRegionType;
+use x86_64::{PhysAddr, structures::paging::{FrameDeallocator, PhysFrame}};
+use rustos::{QemuExitCode, exit_qemu, serial_println, serial_print};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+
+    serial_print!("free_reserved_frame::free_reserved_frame...\t");
+    // Any frame the bootloader didn't mark usable, e.g. the ones its page tables live in
+    let reserved = boot_info
+        .memory_map
+        .iter()
+        .find(|r| r.region_type != MemoryRegionType::Usable && r.range.start_addr() != r.range.end_addr())
+        .expect("no reserved region in the memory map");
+    let frame = PhysFrame::containing_address(PhysAddr::new(reserved.range.start_addr()));
+    rustos::memory::with_frame_allocator(|allocator| unsafe { allocator.deallocate_frame(frame) });
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// Freeing a frame the allocator never owned must panic
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rustos::interrupts::exceptions::{self, CrashReport, Exception};
+use rustos::{exit_qemu, QemuExitCode, serial_print, serial_println};
+
+// Called by the kernel's handler once it printed the report, instead of halting
+fn check_report(report: &CrashReport) {
+    if report.exception == Exception::GENERAL_PROTECTION_FAULT {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("unexpected exception: {}", report.exception.name);
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("general_protection_fault::report_non_canonical_access...\t");
+
+    rustos::init_memory(boot_info);
+    rustos::init();
+    exceptions::set_crash_hook(check_report);
+
+    // Addresses between the lower and upper half aren't canonical, using them is a #GP rather than a #PF
+    unsafe { core::ptr::write_volatile(0x8000_0000_0000 as *mut u64, 42) };
+
+    serial_println!("[failed]");
+    panic!("Execution continued after the exception");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
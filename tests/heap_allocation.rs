@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use rustos::allocator::HEAP_SIZE;
+
+// The allocation count of the loops below, fixed so they don't get slower when the heap grows
+const BOX_COUNT: usize = 100 * 1024;
+// What each of them allocates, big enough that all of them together don't fit in the heap
+type Boxed = [usize; 4];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Test that a couple of simple allocations work and hold their values
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+// Test a large allocation that needs to be reallocated as it grows
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+// Test many small allocations, more than would fit in the heap if freed memory wasn't reused
+#[test_case]
+fn many_boxes() {
+    assert!(BOX_COUNT * core::mem::size_of::<Boxed>() > HEAP_SIZE);
+    for i in 0..BOX_COUNT {
+        let x: Box<Boxed> = Box::new([i; 4]);
+        assert_eq!(x[3], i);
+    }
+}
+
+// Test that freed memory is reused even while another allocation stays alive the whole time
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..BOX_COUNT {
+        let x: Box<Boxed> = Box::new([i; 4]);
+        assert_eq!(x[3], i);
+    }
+    assert_eq!(*long_lived, 1);
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use core::time::Duration;
+use alloc::string::String;
+use bootloader::{BootInfo, entry_point};
+use rustos::interrupts::{irq, stats};
+use rustos::time::{self, pit};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Test that every timer interrupt is counted on its vector, and listed in the table
+#[test_case]
+fn timer_interrupts_counted() {
+    let vector = irq::vector(pit::IRQ);
+    let before = stats::interrupts(vector);
+    time::sleep(Duration::from_millis(50));
+    let counted = stats::interrupts(vector) - before;
+    assert!((45..=60).contains(&counted), "{} timer interrupts in 50ms", counted);
+
+    let mut table = String::new();
+    stats::write_table(&mut table).unwrap();
+    assert!(table.lines().any(|line| line.ends_with("IRQ 0")), "no timer line in:\n{}", table);
+}
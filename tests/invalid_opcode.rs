@@ -0,0 +1,42 @@
+#![feature(core_intrinsics)]
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rustos::interrupts::exceptions::{self, CrashReport, Exception};
+use rustos::{exit_qemu, QemuExitCode, serial_print, serial_println};
+
+// Called by the kernel's handler once it printed the report, instead of halting
+fn check_report(report: &CrashReport) {
+    if report.exception == Exception::INVALID_OPCODE {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("unexpected exception: {}", report.exception.name);
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+entry_point!(main);
+
+#[allow(unreachable_code)] // The failure report is only reached if 'ud2' doesn't fault
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("invalid_opcode::report_ud2...\t");
+
+    rustos::init_memory(boot_info);
+    rustos::init();
+    exceptions::set_crash_hook(check_report);
+
+    // Compiles to 'ud2'
+    core::intrinsics::abort();
+
+    serial_println!("[failed]");
+    panic!("Execution continued after the exception");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use x86_64::{structures::paging::{Page, PageTableFlags}, VirtAddr};
+use rustos::memory::{address_space::{self, AddressSpace}, stack, stats, vmalloc};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Uses most of the memory subsystem and gives everything back
+fn scenario() {
+    let values: Vec<Box<u64>> = (0..100).map(Box::new).collect();
+    drop(values);
+
+    let addr = vmalloc::vmalloc(5 * 4096, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+    unsafe { vmalloc::vfree(addr) };
+
+    let kernel_stack = stack::allocate_stack(2).unwrap();
+    unsafe { stack::free_stack(kernel_stack) };
+
+    let mut parent = AddressSpace::new().unwrap();
+    for i in 0..3 {
+        let page = Page::containing_address(VirtAddr::new(address_space::USER_START + i * 4096));
+        parent.map(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+    }
+    let child = parent.clone_cow().unwrap();
+    drop(parent);
+    drop(child);
+}
+
+// Test that a scenario touching the heap, vmalloc and address spaces doesn't leak
+#[test_case]
+fn scenario_frees_everything() {
+    // The first run may grow long lived bookkeeping (BTreeMap nodes and the like)
+    scenario();
+
+    let before = stats::report();
+    scenario();
+    let after = stats::report();
+    assert_eq!(
+        before.data_frames(), after.data_frames(),
+        "scenario leaked frames\nbefore:\n{}\nafter:\n{}", before, after
+    );
+    assert_eq!(
+        before.heap.bytes_in_use, after.heap.bytes_in_use,
+        "scenario leaked heap memory\nbefore:\n{}\nafter:\n{}", before, after
+    );
+    assert!(after.freed_everything_since(&before));
+    assert!(after.heap.allocations > before.heap.allocations);
+    assert!(after.frames.allocations > before.frames.allocations);
+}
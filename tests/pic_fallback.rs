@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rustos::{apic, interrupts::{self, Controller}, time};
+
+entry_point!(main);
+
+// Pretends there's no APIC, so the kernel has to stay with the 8259 PICs
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    interrupts::force_pic();
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Test that the PICs stayed in charge
+#[test_case]
+fn uses_pic() {
+    assert_eq!(interrupts::controller(), Controller::Pic);
+    assert!(apic::LOCAL_APIC.lock().is_none());
+}
+
+// Test that the timer keeps firing through the PIC, which only works if the EOIs arrive
+#[test_case]
+fn timer_ticks_through_pic() {
+    let start = time::ticks();
+    while time::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+use core::panic::PanicInfo;
+use core::time::Duration;
+use bootloader::{BootInfo, entry_point};
+use rustos::time::{self, rtc};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Test that the wall clock starts from the RTC and moves on with the monotonic clock
+#[test_case]
+fn wall_clock_follows_rtc() {
+    let clock = time::wall_clock();
+    assert!(clock.year >= 2021, "wall clock at {}", clock);
+    assert!((1..=12).contains(&clock.month) && (1..=31).contains(&clock.day), "wall clock at {}", clock);
+
+    let start = time::unix_time();
+    time::sleep(Duration::from_millis(20));
+    assert!(time::unix_time() - start >= Duration::from_millis(19));
+    // The RTC only counts seconds, so it can be a second ahead of the boot time estimate
+    let rtc_seconds = rtc::read().unix_timestamp();
+    let wall_seconds = time::unix_time().as_secs();
+    assert!(rtc_seconds >= wall_seconds.saturating_sub(1) && rtc_seconds <= wall_seconds + 1);
+}
+
+// Test that the periodic interrupt on IRQ 8 fires at the programmed rate
+#[test_case]
+fn periodic_interrupt_ticks() {
+    let frequency = rtc::enable_periodic_interrupt(6);
+    assert_eq!(frequency, 1024);
+
+    let start = rtc::periodic_ticks();
+    time::sleep(Duration::from_millis(100));
+    let ticks = rtc::periodic_ticks() - start;
+    rtc::disable_periodic_interrupt();
+    assert!((90..=115).contains(&ticks), "{} RTC ticks in 100ms", ticks);
+}
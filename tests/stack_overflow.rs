@@ -3,6 +3,7 @@
 #![no_main]
 
 use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use rustos::{exit_qemu, QemuExitCode, serial_print, serial_println};
@@ -27,10 +28,13 @@ extern "x86-interrupt" fn test_double_fault_handler(_stack_frame: InterruptStack
     loop {}
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
 
+    // The double fault stack is mapped from the kernel's frame allocator
+    rustos::init_memory(boot_info);
     rustos::gdt::init();
     init_test_idt();
 
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"] // Rename the generated test 'main' function
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use bootloader::{BootInfo, entry_point};
+use rustos::time::{self, pit};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init_memory(boot_info);
+    rustos::init();
+
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Test that the tick rate matches an interval timed independently on PIT channel 2
+#[test_case]
+fn ticks_match_calibrated_interval() {
+    let start = time::ticks();
+    pit::wait_cycles(pit::FREQUENCY as u64 / 10); // 100ms
+    let elapsed = time::ticks() - start;
+    let expected = time::TICK_HZ as u64 / 10;
+    assert!(elapsed >= expected - 2 && elapsed <= expected + 2, "{} ticks in 100ms", elapsed);
+}
+
+// Test that sleeping lasts at least as long as asked for
+#[test_case]
+fn sleep_waits() {
+    let start = time::uptime();
+    time::sleep(Duration::from_millis(20));
+    let slept = time::uptime() - start;
+    assert!(slept >= Duration::from_millis(19) && slept < Duration::from_millis(30), "slept {:?}", slept);
+}
+
+static PERIODIC: AtomicU64 = AtomicU64::new(0);
+static ONE_SHOT: AtomicU64 = AtomicU64::new(0);
+
+// Test that periodic callbacks keep running until cancelled and one-shot ones run once
+#[test_case]
+fn callbacks_run() {
+    let periodic = time::every(Duration::from_millis(5), || { PERIODIC.fetch_add(1, Ordering::SeqCst); }).unwrap();
+    time::after(Duration::from_millis(10), || { ONE_SHOT.fetch_add(1, Ordering::SeqCst); }).unwrap();
+
+    time::sleep(Duration::from_millis(52));
+    assert!(time::cancel(periodic));
+    assert!(!time::cancel(periodic));
+    let runs = PERIODIC.load(Ordering::SeqCst);
+    assert!((9..=11).contains(&runs), "{} periodic runs", runs);
+    assert_eq!(ONE_SHOT.load(Ordering::SeqCst), 1);
+
+    time::sleep(Duration::from_millis(10));
+    assert_eq!(PERIODIC.load(Ordering::SeqCst), runs);
+}
+
+// Test that 'now' measures an interval timed on PIT channel 2 closely and never goes backwards
+#[test_case]
+fn now_is_precise() {
+    let start = time::now();
+    pit::wait_cycles(pit::FREQUENCY as u64 / 100); // 10ms
+    let elapsed = time::now() - start;
+    // Only the tick based fallback is off by up to a tick
+    let slack = match time::clock_source() {
+        time::ClockSource::Ticks => Duration::from_millis(2),
+        _ => Duration::from_micros(500),
+    };
+    let expected = Duration::from_millis(10);
+    assert!(elapsed > expected - slack && elapsed < expected + slack, "{:?} for 10ms", elapsed);
+
+    let mut last = time::now();
+    for _ in 0..1000 {
+        let now = time::now();
+        assert!(now >= last);
+        last = now;
+    }
+}